@@ -24,7 +24,6 @@
 ///         id
 ///     )
 /// ```
-use bincode::config::standard;
 use cases::{CONFIG, Case, kv_sep_partition_option};
 use clickhouse::{Client, Row};
 use fjall::Config;
@@ -50,17 +49,9 @@ async fn main() {
     for i in db.iter() {
         let (k, v) = i.unwrap();
         let id = u32::from_be_bytes(k[..].try_into().unwrap());
-        let (case, _): (Case, _) = bincode::decode_from_slice(&v, standard()).unwrap();
-        let judgment_date = case
-            .judgment_date
-            .parse::<Date>()
-            .ok()
-            .map(|d| d.since(date(1970, 1, 1)).unwrap().get_days() as u16);
-        let public_date = case
-            .public_date
-            .parse::<Date>()
-            .ok()
-            .map(|d| d.since(date(1970, 1, 1)).unwrap().get_days() as u16);
+        let case = Case::decode(&v).unwrap();
+        let judgment_date = case.judgment_date.map(days_since_epoch);
+        let public_date = case.public_date.map(days_since_epoch);
         let new_case = NewCase {
             id,
             case_id: (!case.case_id.is_empty()).then_some(case.case_id),
@@ -70,7 +61,7 @@ async fn main() {
             procedure: (!case.procedure.is_empty()).then_some(case.procedure),
             judgment_date,
             public_date,
-            parties: (!case.parties.is_empty()).then_some(case.parties),
+            parties: (!case.parties.is_empty()).then(|| case.parties.join("，")),
             cause: (!case.cause.is_empty()).then_some(case.cause),
         };
 
@@ -96,6 +87,11 @@ async fn main() {
     println!("Done, time: {}", time.elapsed().as_secs())
 }
 
+/// ClickHouse `Date`, days since 1970-01-01.
+fn days_since_epoch(d: Date) -> u16 {
+    d.since(date(1970, 1, 1)).unwrap().get_days() as u16
+}
+
 #[derive(Debug, Row, Serialize, Deserialize)]
 struct NewCase {
     id: u32,
@@ -5,11 +5,13 @@
 use jieba_rs::Jieba;
 use tracing::info;
 
-use cases::{CONFIG, Case};
+use cases::{CONFIG, Columns, FullText, Rejects, sources};
 use std::{
     collections::{HashMap, HashSet},
     fs::{self, read_to_string},
     io::Write,
+    ops::ControlFlow,
+    path::Path,
 };
 
 fn main() {
@@ -31,84 +33,74 @@ fn unzip(path: &str) {
         .collect();
     custom_stop_words.extend(stop_words.iter().map(|x| x.to_string()));
 
-    for subdir in fs::read_dir(path).unwrap() {
-        let subdir = subdir.unwrap();
-        let subdir_path = subdir.path().to_str().unwrap().to_string();
-        if subdir_path.ends_with(".zip") {
-            info!("unzipping {}", subdir_path);
-            let file = fs::File::open(&subdir_path).unwrap();
-            let mut archive = zip::ZipArchive::new(file).unwrap();
-
-            for i in 0..archive.len() {
-                let file = archive.by_index(i).unwrap();
-                let raw_name = file.name();
-                if raw_name.ends_with(".csv") {
-                    let mut rdr = csv::Reader::from_reader(file);
-                    let mut j = 0;
-                    for result in rdr.deserialize() {
-                        let mut case: Case = result.unwrap();
-                        id += 1;
-                        j += 1;
-                        case.full_text =
-                            case.full_text
-                                .split_whitespace()
-                                .fold(String::new(), |mut acc, x| {
-                                    acc.push_str("<p>");
-                                    acc.push_str(x);
-                                    acc.push_str("</p>");
-                                    acc
-                                });
-
-                        let meta = [
-                            case.case_id,
-                            case.case_name,
-                            case.court,
-                            case.case_type,
-                            case.procedure,
-                            case.judgment_date,
-                            case.public_date,
-                            case.parties,
-                            case.cause,
-                            case.legal_basis,
-                        ]
-                        .join("\n");
-
-                        let fulltext = case.full_text;
-
-                        let meta_words = jieba.cut(&meta, false);
-                        let fulltext_words = jieba.cut(&fulltext, false);
-
-                        for token in meta_words {
-                            if custom_stop_words.contains(token.word) {
-                                continue;
-                            }
-                            let count = meta_count.entry(token.word.to_owned()).or_insert(0);
-                            *count += 1;
+    let columns = Columns::default();
+    let rejects = Rejects::open(CONFIG.rejects_path());
+    for source in sources(Path::new(path)) {
+        for entry in source.entries().unwrap() {
+            info!("reading {} {}", source.path().display(), entry.name);
+            let mut j = 0;
+            source
+                .for_each(&entry.name, &columns, &rejects, &mut |case| {
+                    id += 1;
+                    j += 1;
+                    let meta = [
+                        case.case_id,
+                        case.case_name,
+                        case.court,
+                        case.case_type,
+                        case.procedure,
+                        case.judgment_date,
+                        case.public_date,
+                        case.parties,
+                        case.cause,
+                        case.legal_basis,
+                    ]
+                    .join("\n");
+
+                    let fulltext = FullText::from_raw(&case.full_text).to_text();
+
+                    let meta_words = jieba.cut(&meta, false);
+                    let fulltext_words = jieba.cut(&fulltext, false);
+
+                    for token in meta_words {
+                        if custom_stop_words.contains(token.word) {
+                            continue;
                         }
+                        let count = meta_count.entry(token.word.to_owned()).or_insert(0);
+                        *count += 1;
+                    }
 
-                        for token in fulltext_words {
-                            if custom_stop_words.contains(token.word) {
-                                continue;
-                            }
-                            let count = fulltext_count.entry(token.word.to_owned()).or_insert(0);
-                            *count += 1;
+                    for token in fulltext_words {
+                        if custom_stop_words.contains(token.word) {
+                            continue;
                         }
+                        let count = fulltext_count.entry(token.word.to_owned()).or_insert(0);
+                        *count += 1;
+                    }
 
-                        if j % 1000 == 0 {
-                            info!("{} {} {}", id, j, time.elapsed().as_secs_f64());
-                        }
+                    if j % 1000 == 0 {
+                        info!("{} {} {}", id, j, time.elapsed().as_secs_f64());
+                    }
 
-                        if j > 10000 {
-                            break;
-                        }
+                    if j > 10000 {
+                        return ControlFlow::Break(());
                     }
-                }
-            }
+                    ControlFlow::Continue(())
+                })
+                .unwrap();
 
-            info!("done {id} {}", subdir_path);
+            info!("done {id} {} {}", source.path().display(), entry.name);
         }
     }
 
+    if rejects.count() > 0 {
+        info!(
+            "{} rows rejected, see {}",
+            rejects.count(),
+            rejects.path().display()
+        );
+    }
+
     let mut meta_count: Vec<_> = meta_count.into_iter().collect();
     meta_count.sort_by_key(|b| std::cmp::Reverse(b.1));
     let mut fulltext_count: Vec<_> = fulltext_count.into_iter().collect();
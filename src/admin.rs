@@ -0,0 +1,287 @@
+//! Corrections and withdrawals of single cases on the running server, without
+//! a new `convert` and `index`. The endpoints are served only when
+//! `admin_token` is set and expect it as a bearer token.
+//!
+//! The server then holds the index writer, so `index` cannot run beside it.
+//! Changes are committed at once and reach the searches through the reload
+//! policy of the reader. The same writer indexes the batches of the live
+//! ingestion, see [`crate::ingest`].
+
+use std::{
+    borrow::Cow,
+    io,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::{IntoResponse, Response},
+};
+use fjall::{Keyspace, PartitionCreateOptions, PartitionHandle};
+use serde_json::{Map, Value};
+use tantivy::IndexWriter;
+use tracing::{info, warn};
+
+use crate::{
+    AppState, CONFIG, Case, CaseFields, Columns, Ids, Rejects, Store, Tan, export::fields,
+    source_key, sources,
+};
+
+/// Cases stored per write batch when ingesting.
+const BATCH_SIZE: usize = 10240;
+
+/// The index writer of the server, and what it needs to store cases.
+pub struct Admin {
+    keyspace: Keyspace,
+    doc_ids: PartitionHandle,
+    writer: Mutex<IndexWriter>,
+    fields: CaseFields,
+}
+
+impl Admin {
+    pub fn open(keyspace: &Keyspace) -> Admin {
+        let index = Tan::index().unwrap();
+        let writer = index
+            .writer(CONFIG.index_memory_mb.unwrap_or(50) * 1024 * 1024)
+            .unwrap();
+        Admin {
+            keyspace: keyspace.clone(),
+            doc_ids: keyspace
+                .open_partition("doc_ids", PartitionCreateOptions::default())
+                .unwrap(),
+            writer: Mutex::new(writer),
+            fields: CaseFields::new(&index.schema()),
+        }
+    }
+
+    /// Sets the fields of case `id` that `object` has, read like a line of a
+    /// JSON Lines source, or restores the case if it was withdrawn, which
+    /// takes at least its full text.
+    fn update(&self, db: &Store, id: u32, object: &Map<String, Value>) -> Result<(), StatusCode> {
+        let columns = Columns::default();
+        let mut writer = self.writer.lock().unwrap();
+        let raw = match db.case(id) {
+            Some(current) => columns.json_update(object, fields(&current).map(Cow::into_owned)),
+            None if !db.is_withdrawn(id) => return Err(StatusCode::NOT_FOUND),
+            None if !columns.json_sets(object, "full_text") => {
+                return Err(StatusCode::UNPROCESSABLE_ENTITY);
+            }
+            None => columns.json_case(object),
+        };
+        let case = Case::from(raw);
+        let doc = self.fields.document(id, &case);
+        let mut batch = self.keyspace.batch();
+        batch.insert(&self.doc_ids, source_key(&case), id.to_be_bytes());
+        batch.remove(&db.withdrawn, id.to_be_bytes());
+        db.insert(&mut batch, id, case);
+        batch.commit().unwrap();
+
+        writer.delete_term(self.fields.id_term(id));
+        // Cases after the last indexed one are left to `index`.
+        if last_indexed(&writer).is_some_and(|last_id| id <= last_id) {
+            writer.add_document(doc).unwrap();
+        }
+        commit(&mut writer, None);
+        Ok(())
+    }
+
+    /// Removes case `id` from the database and the index. False if there is no
+    /// such case.
+    fn delete(&self, db: &Store, id: u32) -> bool {
+        let mut writer = self.writer.lock().unwrap();
+        if !db.contains(id) {
+            return false;
+        }
+        let mut batch = self.keyspace.batch();
+        db.withdraw(&mut batch, id);
+        batch.commit().unwrap();
+
+        writer.delete_term(self.fields.id_term(id));
+        commit(&mut writer, None);
+        true
+    }
+
+    /// Stores the new cases of the source file `path`, as `convert` would, and
+    /// indexes them. Returns the number of new cases, or the error that
+    /// stopped reading the file, after indexing the cases stored before it.
+    pub(crate) fn ingest(
+        &self,
+        db: &Store,
+        ids: &Ids,
+        path: &std::path::Path,
+    ) -> io::Result<usize> {
+        let columns = Columns::default();
+        let rejects = Rejects::open(CONFIG.rejects_path());
+        let name = path.file_name().unwrap().to_string_lossy();
+        let mut added = 0;
+        let mut batch = self.keyspace.batch();
+        let mut read = || -> io::Result<()> {
+            for source in sources(path) {
+                for entry in source.entries()? {
+                    let rows = source.count(&entry.name, &columns)?;
+                    let key = format!("ingest\0{name}\0{}", entry.name);
+                    let first_id = ids.reserve(&key, entry.crc32, rows);
+                    let mut position = first_id;
+                    source.for_each(&entry.name, &columns, &rejects, &mut |raw| {
+                        if position == first_id + rows {
+                            warn!("{name} {} changed while ingesting", entry.name);
+                            return ControlFlow::Break(());
+                        }
+                        let id = position;
+                        position += 1;
+                        let case = Case::from(raw);
+                        if ids.get(&source_key(&case)).is_some()
+                            || db.contains(id)
+                            || db.is_withdrawn(id)
+                        {
+                            return ControlFlow::Continue(());
+                        }
+                        ids.stage(&mut batch, db, id, case);
+                        added += 1;
+                        if added % BATCH_SIZE == 0 {
+                            std::mem::replace(&mut batch, self.keyspace.batch())
+                                .commit()
+                                .unwrap();
+                        }
+                        ControlFlow::Continue(())
+                    })?;
+                }
+            }
+            Ok(())
+        };
+        let result = read();
+        batch.commit().unwrap();
+        added -= ids.resolve(db);
+        self.index_new(db);
+        result.map(|()| added)
+    }
+
+    /// Indexes the new cases stored below the last indexed one, then those
+    /// after it, and commits.
+    pub(crate) fn index_new(&self, db: &Store) {
+        let mut writer = self.writer.lock().unwrap();
+        let start = last_indexed(&writer).map_or(0, |id| id + 1);
+        let mut indexed = db.unindexed_before(start);
+        let mut count = 0;
+        for &id in &indexed {
+            let key = id.to_be_bytes();
+            let Some(v) = db.cases.get(key).unwrap() else {
+                continue;
+            };
+            let case = db.decode(&key, &v, CONFIG.index_with_full_text);
+            // Possibly indexed already, if a commit came before clearing it.
+            writer.delete_term(self.fields.id_term(id));
+            writer
+                .add_document(self.fields.document(id, &case))
+                .unwrap();
+            count += 1;
+        }
+        let mut last_id = None;
+        for i in db.cases.range(start.to_be_bytes()..) {
+            let (k, v) = i.unwrap();
+            let id = u32::from_be_bytes(k[..].try_into().unwrap());
+            let case = db.decode(&k, &v, CONFIG.index_with_full_text);
+            writer
+                .add_document(self.fields.document(id, &case))
+                .unwrap();
+            if db.to_index.contains_key(&k).unwrap() {
+                indexed.push(id);
+            }
+            last_id = Some(id);
+            count += 1;
+        }
+        if indexed.is_empty() && last_id.is_none() {
+            return;
+        }
+        commit(&mut writer, last_id);
+        let mut batch = self.keyspace.batch();
+        db.indexed(&mut batch, &indexed);
+        batch.commit().unwrap();
+        info!("indexed {count} cases");
+    }
+}
+
+/// The last case `index` has indexed, from the payload of the last commit.
+fn last_indexed(writer: &IndexWriter) -> Option<u32> {
+    let metas = writer.index().load_metas().unwrap();
+    metas.payload.and_then(|payload| payload.parse().ok())
+}
+
+/// Commits with `last_id` as the payload `index` resumes from, or keeping the
+/// current one.
+fn commit(writer: &mut IndexWriter, last_id: Option<u32>) {
+    let payload = match last_id {
+        Some(last_id) => Some(last_id.to_string()),
+        None => writer.index().load_metas().unwrap().payload,
+    };
+    let mut prepared = writer.prepare_commit().unwrap();
+    if let Some(payload) = payload {
+        prepared.set_payload(&payload);
+    }
+    prepared.commit().unwrap();
+}
+
+/// The admin side of the state, if the request carries the admin token.
+pub(crate) fn authorize(state: &AppState, headers: &HeaderMap) -> Result<Arc<Admin>, StatusCode> {
+    let (Some(admin), Some(token)) = (&state.admin, &CONFIG.admin_token) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let bearer = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if bearer != Some(token.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(admin.clone())
+}
+
+/// `PUT /admin/case/{id}`: sets the fields of case `id` in the JSON object of
+/// the body, read like a line of a JSON Lines source. Fields the object leaves
+/// out are kept.
+pub async fn admin_update_case(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u32>,
+    Json(object): Json<Map<String, Value>>,
+) -> Response {
+    let admin = match authorize(&state, &headers) {
+        Ok(admin) => admin,
+        Err(status) => return status.into_response(),
+    };
+    let updated = tokio::task::spawn_blocking(move || admin.update(&state.db, id, &object))
+        .await
+        .unwrap();
+    match updated {
+        Ok(()) => {
+            info!("admin updated case {id}");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(StatusCode::NOT_FOUND) => (StatusCode::NOT_FOUND, "Not found").into_response(),
+        Err(status) => (status, "A withdrawn case takes its full_text").into_response(),
+    }
+}
+
+/// `DELETE /admin/case/{id}`: withdraws case `id`.
+pub async fn admin_delete_case(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<u32>,
+) -> Response {
+    let admin = match authorize(&state, &headers) {
+        Ok(admin) => admin,
+        Err(status) => return status.into_response(),
+    };
+    let deleted = tokio::task::spawn_blocking(move || admin.delete(&state.db, id))
+        .await
+        .unwrap();
+    if deleted {
+        info!("admin withdrew case {id}");
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        (StatusCode::NOT_FOUND, "Not found").into_response()
+    }
+}
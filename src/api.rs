@@ -0,0 +1,193 @@
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Redirect},
+};
+use jiff::civil::Date;
+use serde::Serialize;
+use tracing::info;
+
+#[cfg(feature = "vsearch")]
+use crate::controller::QueryCase;
+use crate::{
+    AppState, Case, Paragraph,
+    controller::{
+        FacetGroup, Filters, QuerySearch, SearchOutput, canonical_id, case_detail, load_cases,
+        search_ids,
+    },
+};
+
+#[derive(Serialize)]
+pub struct SearchResponse {
+    search: String,
+    search_type: String,
+    sort: &'static str,
+    filters: Filters,
+    total: usize,
+    offset: usize,
+    /// Pass as `cursor` to get the next page; absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_cursor: Option<String>,
+    hits: Vec<Hit>,
+    facets: Vec<FacetGroup>,
+}
+
+#[derive(Serialize)]
+pub struct Hit {
+    id: u32,
+    /// BM25 or vector similarity, absent when sorted by date.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    preview: String,
+    case: CaseMeta,
+}
+
+/// Case metadata without the full text, with English field names.
+#[derive(Serialize)]
+pub struct CaseMeta {
+    doc_id: String,
+    case_id: String,
+    case_name: String,
+    court: String,
+    region: String,
+    case_type: String,
+    case_type_code: String,
+    source: String,
+    procedure: String,
+    judgment_date: Option<Date>,
+    public_date: Option<Date>,
+    parties: Vec<String>,
+    cause: String,
+    legal_basis: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct CaseResponse {
+    id: u32,
+    case: CaseMeta,
+    /// Full text as HTML.
+    html: String,
+    /// Full text as plain text, one paragraph per line.
+    text: String,
+    /// Full text as header, body and signature paragraphs.
+    paragraphs: Vec<Paragraph>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    similar: Option<Vec<Similar>>,
+}
+
+#[derive(Serialize)]
+pub struct Similar {
+    id: u32,
+    case_name: String,
+    case_id: String,
+}
+
+impl From<Case> for CaseMeta {
+    fn from(case: Case) -> Self {
+        CaseMeta {
+            doc_id: case.doc_id,
+            case_id: case.case_id,
+            case_name: case.case_name,
+            court: case.court,
+            region: case.region,
+            case_type: case.case_type,
+            case_type_code: case.case_type_code,
+            source: case.source,
+            procedure: case.procedure,
+            judgment_date: case.judgment_date,
+            public_date: case.public_date,
+            parties: case.parties,
+            cause: case.cause,
+            legal_basis: case.legal_basis,
+        }
+    }
+}
+
+/// `/api/search`, takes the same parameters as `/` and returns JSON.
+pub async fn api_search(
+    Query(mut input): Query<QuerySearch>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    input.export = None;
+    let SearchOutput {
+        search,
+        search_type,
+        offset,
+        total,
+        sort,
+        filters,
+        next_cursor,
+        hits: scores,
+        facets,
+        snippets,
+        ..
+    } = search_ids(input, &state).await;
+
+    let hits = load_cases(&state, scores.keys(), snippets.as_ref())
+        .into_iter()
+        .map(|(id, preview, case)| Hit {
+            id,
+            score: scores[&id],
+            preview,
+            case: case.into(),
+        })
+        .collect();
+
+    Json(SearchResponse {
+        search,
+        search_type,
+        sort: sort.as_str(),
+        filters,
+        total,
+        offset,
+        next_cursor,
+        hits,
+        facets,
+    })
+}
+
+/// `/api/case/{id}`, the decoded case with its full text as HTML and plain text.
+///
+/// With the `vsearch` feature, similar cases are included unless `with_similar=false`.
+pub async fn api_case(
+    #[cfg(feature = "vsearch")] Query(params): Query<QueryCase>,
+    State(state): State<AppState>,
+    Path(id): Path<u32>,
+) -> impl IntoResponse {
+    info!("api id: {}", id);
+    if let Some(canonical) = canonical_id(&state, id) {
+        return Redirect::permanent(&format!("/api/case/{canonical}")).into_response();
+    }
+    #[cfg(feature = "vsearch")]
+    let with_similar = params.with_similar.unwrap_or(true);
+    #[cfg(not(feature = "vsearch"))]
+    let with_similar = false;
+
+    let Some(mut detail) = case_detail(id, &state, with_similar).await else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+
+    let full_text = std::mem::take(&mut detail.case.full_text);
+    let similar = detail.enable_similar.then(|| {
+        detail
+            .similar_cases
+            .into_iter()
+            .map(|(id, case_name, case_id)| Similar {
+                id,
+                case_name,
+                case_id,
+            })
+            .collect()
+    });
+
+    Json(CaseResponse {
+        id,
+        case: detail.case.into(),
+        html: full_text.to_html(),
+        text: full_text.to_text(),
+        paragraphs: full_text.0,
+        similar,
+    })
+    .into_response()
+}
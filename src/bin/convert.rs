@@ -1,8 +1,16 @@
-use bincode::config::standard;
-use cases::{CONFIG, Case, kv_sep_partition_option};
-use fjall::Config;
-use std::fs;
-use tracing::info;
+use bincode::{Decode, Encode, config::standard};
+use cases::{CONFIG, Case, Columns, Entry, Ids, Rejects, Source, Store, source_key, sources};
+use fjall::{Config, Keyspace, PartitionCreateOptions, PartitionHandle};
+use std::{
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+use tracing::{info, warn};
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt};
 
 #[cfg(not(target_os = "windows"))]
@@ -14,98 +22,276 @@ fn main() {
         .with(tracing_subscriber::EnvFilter::new("info,fjall=warn"))
         .with(tracing_subscriber::fmt::layer())
         .init();
+    if std::env::args().nth(2).as_deref() == Some("manifest") {
+        print_manifest(&CONFIG.db);
+        return;
+    }
     convert(CONFIG.raw_data_path.as_ref().unwrap(), &CONFIG.db);
 }
 
 fn convert(raw_path: &str, db_path: &str) {
     let time = std::time::Instant::now();
-    let mut ft = Vec::with_capacity(1024);
-    let mut id: u32 = 0;
     let keyspace = Config::new(db_path)
         .max_write_buffer_size(256_000_000)
         .open()
         .unwrap();
-    let db = keyspace
-        .open_partition("cases", kv_sep_partition_option())
+    let db = Store::open(&keyspace);
+    let manifest = keyspace
+        .open_partition("manifest", PartitionCreateOptions::default())
         .unwrap();
 
-    for subdir in fs::read_dir(raw_path).unwrap() {
-        let subdir = subdir.unwrap();
-        let subdir_path = subdir.path().to_str().unwrap().to_string();
-        if subdir_path.ends_with(".zip") {
-            info!("unzipping {}", subdir_path);
-            let file = fs::File::open(&subdir_path).unwrap();
-            let mut archive = zip::ZipArchive::new(file).unwrap();
-
-            let mut buf = String::new();
-            for i in 0..archive.len() {
-                let file = archive.by_index(i).unwrap();
-                let raw_name = file.name();
-                if raw_name.ends_with(".csv") {
-                    let mut rdr = csv::Reader::from_reader(file);
-                    for result in rdr.deserialize() {
-                        id += 1;
-                        if db.contains_key(id.to_be_bytes()).unwrap() {
-                            if id.is_multiple_of(10000) {
-                                info!("skipping {}", id);
-                            }
-                            continue;
-                        }
-
-                        let mut case: Case = result.unwrap();
-                        // https://wenshu.court.gov.cn/website/wenshu/181107ANFZ0BXSK4/index.html?docId=964fc681687d4e47a0a9ace500096dde
-                        case.doc_id = case
-                            .doc_id
-                            .rsplit_once("=")
-                            .unwrap_or_default()
-                            .1
-                            .to_string();
-
-                        case.full_text.split_whitespace().for_each(|word| {
-                            buf.push_str("<p>");
-                            buf.push_str(word);
-                            buf.push_str("</p>");
-                        });
-
-                        case.full_text = buf.clone();
-                        buf.clear();
-
-                        ft.push((id, case));
-
-                        if ft.len() >= 10240 {
-                            info!("inserting {id}, time: {}", time.elapsed().as_secs());
-                            let mut batch = keyspace.batch();
-                            for (id, case) in ft.iter() {
-                                batch.insert(
-                                    &db,
-                                    (*id).to_be_bytes(),
-                                    bincode::encode_to_vec(case, standard()).unwrap(),
-                                );
-                            }
-                            batch.commit().unwrap();
-                            ft.clear();
-                        }
-                    }
+    let ids = Ids::open(&keyspace);
+    if ids.needs_bootstrap() {
+        ids.bootstrap(&db);
+    }
+    // Cases stored by an interrupted run.
+    ids.resolve(&db);
+
+    let sources = sources(Path::new(raw_path));
+    let threads = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(4);
+
+    let converter = Converter {
+        ids,
+        keyspace,
+        db,
+        manifest,
+        columns: Columns::default(),
+        rejects: Rejects::open(CONFIG.rejects_path()),
+        raw_path: PathBuf::from(raw_path),
+        time,
+    };
+    let entries = parallel_map(&sources, threads, |source| source.entries());
+    let jobs: Vec<_> = sources
+        .iter()
+        .zip(entries)
+        .filter_map(|(source, entries)| match entries {
+            Ok(entries) => Some(entries.into_iter().map(move |entry| (source, entry))),
+            Err(e) => {
+                warn!("skipping {}: {e}", source.path().display());
+                None
+            }
+        })
+        .flatten()
+        .filter(|(source, entry)| !converter.is_imported(source.as_ref(), entry))
+        .collect();
+
+    // Ids are reserved through the entries in order, so each entry gets the
+    // range after the rows of the entries before it, whichever thread runs it.
+    let counts = parallel_map(&jobs, threads, |(source, entry)| {
+        source.count(&entry.name, &converter.columns)
+    });
+    let jobs: Vec<_> = jobs
+        .into_iter()
+        .zip(counts)
+        .filter_map(|((source, entry), rows)| match rows {
+            Ok(rows) => Some((source, entry, rows)),
+            Err(e) => {
+                warn!("skipping {} {}: {e}", source.path().display(), entry.name);
+                None
+            }
+        })
+        .map(|(source, entry, rows)| {
+            let key = manifest_key(&converter.raw_path, source.path(), &entry.name);
+            let first_id = converter.ids.reserve(&key, entry.crc32, rows);
+            (source, entry, first_id, rows)
+        })
+        .collect();
+    info!(
+        "{} rows in {} entries, time: {}",
+        jobs.iter().map(|(.., rows)| *rows as u64).sum::<u64>(),
+        jobs.len(),
+        time.elapsed().as_secs()
+    );
+    parallel_map(&jobs, threads, |(source, entry, first_id, rows)| {
+        converter.convert_entry(source.as_ref(), entry, *first_id, *rows);
+    });
+    converter.ids.resolve(&converter.db);
+
+    let rejects = &converter.rejects;
+    if rejects.count() > 0 {
+        info!(
+            "Done, {} rows rejected, see {}",
+            rejects.count(),
+            rejects.path().display()
+        );
+    } else {
+        info!("Done");
+    }
+}
+
+/// Manifest record of an imported entry, keyed by [`manifest_key`].
+#[derive(Encode, Decode)]
+struct Imported {
+    rows: u64,
+    /// Smallest and largest id of the rows, new or already stored.
+    first_id: u32,
+    last_id: u32,
+    crc32: u32,
+    completed_at: String,
+}
+
+/// Source path relative to `raw_data_path`, NUL, entry name.
+fn manifest_key(raw_path: &Path, path: &Path, entry: &str) -> String {
+    let path = match path.strip_prefix(raw_path) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative,
+        _ => path,
+    };
+    format!("{}\0{entry}", path.display())
+}
+
+fn print_manifest(db_path: &str) {
+    let keyspace = Config::new(db_path).open().unwrap();
+    let manifest = keyspace
+        .open_partition("manifest", PartitionCreateOptions::default())
+        .unwrap();
+    println!("source\tentry\trows\tfirst_id\tlast_id\tcrc32\tcompleted_at");
+    for i in manifest.iter() {
+        let (k, v) = i.unwrap();
+        let key = String::from_utf8_lossy(&k);
+        let (source, entry) = key.split_once('\0').unwrap_or((&key, ""));
+        let (imported, _): (Imported, _) = bincode::decode_from_slice(&v, standard()).unwrap();
+        println!(
+            "{source}\t{entry}\t{}\t{}\t{}\t{:08x}\t{}",
+            imported.rows,
+            imported.first_id,
+            imported.last_id,
+            imported.crc32,
+            imported.completed_at
+        );
+    }
+}
+
+/// Runs `f` on every item with `threads` threads, results in item order.
+fn parallel_map<T: Sync, R: Send>(
+    items: &[T],
+    threads: usize,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R> {
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(items.len()));
+    std::thread::scope(|s| {
+        for _ in 0..threads.min(items.len()) {
+            s.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(item) = items.get(i) else {
+                        break;
+                    };
+                    let result = f(item);
+                    results.lock().unwrap().push((i, result));
                 }
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+struct Converter {
+    keyspace: Keyspace,
+    db: Store,
+    /// Entries imported completely, see [`Imported`].
+    manifest: PartitionHandle,
+    ids: Ids,
+    columns: Columns,
+    rejects: Rejects,
+    raw_path: PathBuf,
+    time: Instant,
+}
+
+impl Converter {
+    /// Whether the manifest has `entry` as complete with the same checksum.
+    fn is_imported(&self, source: &dyn Source, entry: &Entry) -> bool {
+        let key = manifest_key(&self.raw_path, source.path(), &entry.name);
+        let Some(v) = self.manifest.get(&key).unwrap() else {
+            return false;
+        };
+        let (imported, _): (Imported, _) = bincode::decode_from_slice(&v, standard()).unwrap();
+        if imported.crc32 != entry.crc32 {
+            return false;
+        }
+        info!(
+            "skipping {} {}, imported at {}",
+            source.path().display(),
+            entry.name,
+            imported.completed_at
+        );
+        true
+    }
+
+    /// Imports the `rows` rows of `entry` at the ids reserved from `first_id`.
+    fn convert_entry(&self, source: &dyn Source, entry: &Entry, first_id: u32, rows: u32) {
+        let path = source.path().display();
+        info!("reading {path} {}, first id {first_id}", entry.name);
+
+        let mut ft = Vec::with_capacity(1024);
+        let mut position = first_id;
+        let mut id = 0;
+        let (mut min_id, mut max_id) = (u32::MAX, 0);
+        let mut changed = false;
+        let read = source.for_each(&entry.name, &self.columns, &self.rejects, &mut |raw| {
+            if position == first_id + rows {
+                warn!("{path} {} changed while converting", entry.name);
+                changed = true;
+                return ControlFlow::Break(());
             }
+            let case = Case::from(raw);
 
-            info!("done {}", subdir_path);
+            let mapped = self.ids.get(&source_key(&case));
+            id = mapped.unwrap_or(position);
+            position += 1;
+            min_id = min_id.min(id);
+            max_id = max_id.max(id);
+            if mapped.is_some() || self.db.contains(id) || self.db.is_withdrawn(id) {
+                if id.is_multiple_of(10000) {
+                    info!("skipping {}", id);
+                }
+                return ControlFlow::Continue(());
+            }
+
+            ft.push((id, case));
+
+            if ft.len() >= 10240 {
+                info!("inserting {id}, time: {}", self.time.elapsed().as_secs());
+                self.insert(&mut ft);
+            }
+            ControlFlow::Continue(())
+        });
+
+        if !ft.is_empty() {
+            info!("inserting {id}, time: {}", self.time.elapsed().as_secs());
+            self.insert(&mut ft);
+        }
+        // Left out of the manifest, so the next run reads it again.
+        if let Err(e) = read {
+            warn!("stopped reading {path} {}: {e}", entry.name);
+            return;
+        } else if changed {
+            return;
         }
+
+        let imported = Imported {
+            rows: rows.into(),
+            first_id: min_id.min(max_id),
+            last_id: max_id,
+            crc32: entry.crc32,
+            completed_at: jiff::Timestamp::now().to_string(),
+        };
+        let key = manifest_key(&self.raw_path, source.path(), &entry.name);
+        self.manifest
+            .insert(key, bincode::encode_to_vec(&imported, standard()).unwrap())
+            .unwrap();
+        info!("done {path} {}, {rows} rows", entry.name);
     }
 
-    if !ft.is_empty() {
-        info!("inserting {id}, time: {}", time.elapsed().as_secs());
-        let mut batch = keyspace.batch();
-        for (id, case) in ft.iter() {
-            batch.insert(
-                &db,
-                (*id).to_be_bytes(),
-                bincode::encode_to_vec(case, standard()).unwrap(),
-            );
+    fn insert(&self, ft: &mut Vec<(u32, Case)>) {
+        let mut batch = self.keyspace.batch();
+        for (id, case) in ft.drain(..) {
+            self.ids.stage(&mut batch, &self.db, id, case);
         }
         batch.commit().unwrap();
-        ft.clear();
     }
-
-    info!("Done");
 }
@@ -0,0 +1,132 @@
+//! Finds near-duplicate cases: copies of a judgment under the same case number
+//! whose texts differ only slightly. Later copies are recorded as aliases of the
+//! first one, which search results and `/case/{id}` then resolve to.
+//!
+//! Run it after `convert`.
+
+use cases::{CONFIG, Store, normalize_case_no, source_key};
+use fjall::{Config, PartitionCreateOptions, Slice};
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt};
+use xxhash_rust::xxh3::xxh3_64;
+
+#[cfg(not(target_os = "windows"))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Most differing SimHash bits for two texts to count as the same judgment.
+const MAX_DISTANCE: u32 = 3;
+
+/// Chars per shingle fed into the SimHash.
+const SHINGLE: usize = 3;
+
+fn main() {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new("info,fjall=warn"))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let time = std::time::Instant::now();
+    let keyspace = Config::new(&CONFIG.db).open().unwrap();
+    let db = Store::open(&keyspace);
+    let doc_ids = keyspace
+        .open_partition("doc_ids", PartitionCreateOptions::default())
+        .unwrap();
+    let redirects = keyspace
+        .open_partition("redirects", PartitionCreateOptions::default())
+        .unwrap();
+
+    // Cases keyed by case number then id, so each case number is a run of keys.
+    if keyspace.partition_exists("dedup_tmp") {
+        let stale = keyspace
+            .open_partition("dedup_tmp", PartitionCreateOptions::default())
+            .unwrap();
+        keyspace.delete_partition(stale).unwrap();
+    }
+    let tmp = keyspace
+        .open_partition("dedup_tmp", PartitionCreateOptions::default())
+        .unwrap();
+    for i in db.cases.iter() {
+        let (k, v) = i.unwrap();
+        if redirects.contains_key(&k).unwrap() {
+            continue;
+        }
+        let case = db.decode(&k, &v, true);
+        let case_no = normalize_case_no(&case.case_id);
+        if case_no.is_empty() {
+            continue;
+        }
+        let mut key = case_no.into_bytes();
+        key.push(0);
+        key.extend_from_slice(&k);
+        tmp.insert(key, simhash(&case.full_text.to_text()).to_be_bytes())
+            .unwrap();
+    }
+    info!(
+        "hashed {} cases, {}",
+        tmp.len().unwrap(),
+        time.elapsed().as_secs()
+    );
+
+    let mut aliases = 0;
+    let mut group: Vec<(u32, u64)> = Vec::new();
+    let mut group_case_no = Vec::new();
+    for i in tmp.iter() {
+        let (k, v) = i.unwrap();
+        let (case_no, id) = k.split_at(k.len() - 5);
+        let id = u32::from_be_bytes(id[1..].try_into().unwrap());
+        let hash = u64::from_be_bytes(v[..].try_into().unwrap());
+        if case_no != group_case_no {
+            group.clear();
+            group_case_no = case_no.to_vec();
+        }
+        // Ids ascend within a case number, so the first copy stays canonical.
+        match group
+            .iter()
+            .find(|(_, h)| (h ^ hash).count_ones() <= MAX_DISTANCE)
+        {
+            Some(&(canonical, _)) => {
+                let canonical = Slice::from(canonical.to_be_bytes());
+                redirects
+                    .insert(id.to_be_bytes(), canonical.clone())
+                    .unwrap();
+                if let Some(case) = db.meta(id) {
+                    doc_ids.insert(source_key(&case), canonical).unwrap();
+                }
+                aliases += 1;
+            }
+            None => group.push((id, hash)),
+        }
+    }
+
+    keyspace.delete_partition(tmp).unwrap();
+    info!(
+        "Done, {aliases} near duplicates, {} redirects in total, {}",
+        redirects.len().unwrap(),
+        time.elapsed().as_secs()
+    );
+}
+
+/// 64-bit SimHash of the char shingles of `text`, whitespace ignored.
+fn simhash(text: &str) -> u64 {
+    let chars: Vec<char> = text.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut weights = [0i32; 64];
+    let mut buf = String::new();
+    for shingle in chars.windows(SHINGLE.min(chars.len()).max(1)) {
+        buf.clear();
+        buf.extend(shingle);
+        let hash = xxh3_64(buf.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, w)| **w > 0)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
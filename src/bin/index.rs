@@ -1,9 +1,12 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Mutex, mpsc::sync_channel},
+};
 
-use bincode::config::standard;
-use cases::{CONFIG, Case, Tan, kv_sep_partition_option, remove_html_tags};
-use fjall::Config;
-use tantivy::TantivyDocument;
+use cases::{CONFIG, CaseFields, Store, Tan};
+use fjall::{Config, Keyspace, Slice};
+use tantivy::{IndexWriter, TantivyDocument};
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -11,6 +14,9 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
+/// Cases per batch handed to a worker.
+const BATCH_SIZE: usize = 1000;
+
 fn main() {
     tracing_subscriber::registry()
         .with(tracing_subscriber::EnvFilter::new(
@@ -19,23 +25,7 @@ fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let schema = Tan::schema();
-
-    let id_field = schema.get_field("id").unwrap();
-    let case_id = schema.get_field("case_id").unwrap();
-    let case_name = schema.get_field("case_name").unwrap();
-    let court = schema.get_field("court").unwrap();
-    let case_type = schema.get_field("case_type").unwrap();
-    let procedure = schema.get_field("procedure").unwrap();
-    let year = schema.get_field("year").unwrap();
-    let month = schema.get_field("month").unwrap();
-    let day = schema.get_field("day").unwrap();
-    let judgment_date = schema.get_field("judgment_date").unwrap();
-    let public_date = schema.get_field("public_date").unwrap();
-    let parties = schema.get_field("parties").unwrap();
-    let cause = schema.get_field("cause").unwrap();
-    let legal_basis = schema.get_field("legal_basis").unwrap();
-    let full_text = schema.get_field("full_text").unwrap();
+    let fields = CaseFields::new(&Tan::schema());
 
     let index_path = Path::new(&CONFIG.index_path);
     if !index_path.exists() {
@@ -43,7 +33,17 @@ fn main() {
     }
 
     let index = Tan::index().unwrap();
-    let mut writer = index.writer(50 * 1024 * 1024).unwrap();
+    let mut writer = index
+        .writer(CONFIG.index_memory_mb.unwrap_or(50) * 1024 * 1024)
+        .unwrap();
+    // Each commit records the last indexed key, so an interrupted or later run
+    // picks up right after it, once it has indexed the new cases stored below
+    // it meanwhile, listed in `to_index`.
+    let mut last_id: Option<u32> = index
+        .load_metas()
+        .unwrap()
+        .payload
+        .and_then(|payload| payload.parse().ok());
 
     let time = std::time::Instant::now();
 
@@ -52,82 +52,141 @@ fn main() {
         .open()
         .unwrap();
 
-    let cases_new = keyspace_new
-        .open_partition("cases", kv_sep_partition_option())
-        .unwrap();
-
-    for i in cases_new.iter() {
-        let (k, v) = i.unwrap();
-        let id = u32::from_be_bytes(k[..].try_into().unwrap());
-        let (mut case, _): (Case, _) = bincode::decode_from_slice(&v, standard()).unwrap();
+    let store = Store::open(&keyspace_new);
+    let cases_new = &store.cases;
 
-        if CONFIG.index_with_full_text {
-            case.full_text = remove_html_tags(&case.full_text);
+    let start = match last_id {
+        Some(last_id) => {
+            info!("resuming after id {last_id}");
+            last_id + 1
         }
+        None => 0,
+    };
 
-        let mut doc = TantivyDocument::default();
-        doc.add_u64(id_field, id as u64);
-        if !case.case_id.is_empty() {
-            doc.add_text(case_id, &case.case_id);
-        }
-        if !case.case_name.is_empty() {
-            doc.add_text(case_name, &case.case_name);
-        }
-        if !case.court.is_empty() {
-            doc.add_text(court, &case.court);
-        }
-        if !case.case_type.is_empty() {
-            doc.add_text(case_type, &case.case_type);
-        }
-        if !case.procedure.is_empty() {
-            doc.add_text(procedure, &case.procedure);
-        }
-        if !case.judgment_date.is_empty() {
-            doc.add_text(judgment_date, &case.judgment_date);
-            let s: Vec<&str> = case.judgment_date.split("-").collect();
-            if let Some(y) = s.first()
-                && let Ok(judge_year) = y.parse()
-            {
-                doc.add_u64(year, judge_year);
-            }
-            if let Some(m) = s.get(1)
-                && let Ok(judge_month) = m.parse()
-            {
-                doc.add_u64(month, judge_month);
+    let staged = store.unindexed_before(start);
+    if !staged.is_empty() {
+        info!("{} new cases stored before id {start}", staged.len());
+    }
+
+    let build = |id: u32, v: &[u8]| -> TantivyDocument {
+        let case = store.decode(&id.to_be_bytes(), v, CONFIG.index_with_full_text);
+        fields.document(id, &case)
+    };
+
+    // reader -> workers -> writer: the reader hands out numbered batches of raw
+    // values, the workers build the documents, and the writer adds them back in
+    // key order so the committed payload is exact.
+    let threads = CONFIG.index_threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4)
+    });
+    // Values are paired with whether the case is listed in `to_index`.
+    let (raw_tx, raw_rx) = sync_channel::<(usize, Vec<(u32, Slice, bool)>)>(threads * 2);
+    let (doc_tx, doc_rx) = sync_channel::<(usize, Vec<(u32, TantivyDocument, bool)>)>(threads * 2);
+    let raw_rx = Mutex::new(raw_rx);
+    let mut count = 0;
+    let mut indexed = Vec::new();
+    std::thread::scope(|s| {
+        let (store, staged) = (&store, &staged);
+        s.spawn(move || {
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut seq = 0;
+            let below = staged.iter().filter_map(|&id| {
+                let v = cases_new.get(id.to_be_bytes()).unwrap()?;
+                Some((id, v, true))
+            });
+            let after = cases_new.range(start.to_be_bytes()..).map(|i| {
+                let (k, v) = i.unwrap();
+                let id = u32::from_be_bytes(k[..].try_into().unwrap());
+                (id, v, store.to_index.contains_key(&k).unwrap())
+            });
+            for item in below.chain(after) {
+                batch.push(item);
+                if batch.len() >= BATCH_SIZE {
+                    raw_tx.send((seq, std::mem::take(&mut batch))).unwrap();
+                    seq += 1;
+                }
             }
-            if let Some(d) = s.get(2)
-                && let Ok(judge_day) = d.parse()
-            {
-                doc.add_u64(day, judge_day);
+            if !batch.is_empty() {
+                raw_tx.send((seq, batch)).unwrap();
             }
+        });
+
+        for _ in 0..threads {
+            let doc_tx = doc_tx.clone();
+            let (raw_rx, build) = (&raw_rx, &build);
+            s.spawn(move || {
+                loop {
+                    let Ok((seq, batch)) = raw_rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let docs = batch
+                        .iter()
+                        .map(|(id, v, staged)| (*id, build(*id, v), *staged))
+                        .collect();
+                    doc_tx.send((seq, docs)).unwrap();
+                }
+            });
         }
-        if !case.public_date.is_empty() {
-            doc.add_text(public_date, &case.public_date);
-        }
-        if !case.parties.is_empty() {
-            doc.add_text(parties, &case.parties);
-        }
-        if !case.cause.is_empty() {
-            doc.add_text(cause, &case.cause);
-        }
-        if !case.legal_basis.is_empty() {
-            doc.add_text(legal_basis, &case.legal_basis);
-        }
-        if CONFIG.index_with_full_text && !case.full_text.is_empty() {
-            doc.add_text(full_text, &case.full_text);
-        }
-        writer.add_document(doc).unwrap();
+        drop(doc_tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for (seq, docs) in doc_rx {
+            pending.insert(seq, docs);
+            while let Some(docs) = pending.remove(&next) {
+                next += 1;
+                for (id, doc, staged) in docs {
+                    if id < start {
+                        // Possibly indexed already, if a commit came before
+                        // clearing it.
+                        writer.delete_term(fields.id_term(id));
+                    } else {
+                        last_id = Some(id);
+                    }
+                    writer.add_document(doc).unwrap();
+                    if staged {
+                        indexed.push(id);
+                    }
+                    count += 1;
 
-        if id % 10000 == 0 {
-            writer.commit().unwrap();
-            info!("{} done, {}", id, time.elapsed().as_secs());
+                    // Some once anything is added: cases are staged below
+                    // `start` only after a commit.
+                    if count % 10000 == 0
+                        && let Some(last_id) = last_id
+                    {
+                        commit(&mut writer, last_id);
+                        clear(store, &keyspace_new, &mut indexed);
+                        info!("{} done, {}", id, time.elapsed().as_secs());
+                    }
+                }
+            }
         }
-    }
+    });
 
-    writer.commit().unwrap();
+    if let Some(last_id) = last_id {
+        commit(&mut writer, last_id);
+        clear(&store, &keyspace_new, &mut indexed);
+    }
     info!(
-        "Total {}, {}",
+        "Total {}, indexed {count}, {}",
         cases_new.approximate_len(),
         time.elapsed().as_secs()
     );
 }
+
+/// Takes the committed `indexed` cases off `to_index`.
+fn clear(store: &Store, keyspace: &Keyspace, indexed: &mut Vec<u32>) {
+    let mut batch = keyspace.batch();
+    store.indexed(&mut batch, indexed);
+    batch.commit().unwrap();
+    indexed.clear();
+}
+
+/// Commits with `last_id`, the last key added to the index, as the payload.
+fn commit(writer: &mut IndexWriter, last_id: u32) {
+    let mut prepared = writer.prepare_commit().unwrap();
+    prepared.set_payload(&last_id.to_string());
+    prepared.commit().unwrap();
+}
@@ -1,12 +1,20 @@
-use axum::{Router, http::StatusCode, routing::get};
-use cases::{AppState, CONFIG, Tan, case, help, kv_sep_partition_option, search, style};
-use fjall::Config;
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    http::StatusCode,
+    routing::{get, post, put},
+};
+use cases::{
+    Admin, AppState, CONFIG, Ids, Store, Tan, admin_delete_case, admin_ingest, admin_update_case,
+    api_case, api_search, case, case_no, doc, help, search, style, watch_ingest,
+};
+use fjall::{Config, PartitionCreateOptions};
 
 use std::{net::SocketAddr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_http::{compression::CompressionLayer, timeout::TimeoutLayer};
-use tracing::info;
+use tracing::{error, info};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 #[cfg(feature = "vsearch")]
@@ -29,9 +37,24 @@ async fn main() {
     let searcher = Arc::new(Tan::searcher().unwrap());
 
     let keyspace = Config::new(CONFIG.db.as_str()).open().unwrap();
-    let db = keyspace
-        .open_partition("cases", kv_sep_partition_option())
+    let db = Store::open(&keyspace);
+    let redirects = keyspace
+        .open_partition("redirects", PartitionCreateOptions::default())
         .unwrap();
+    let admin = (CONFIG.admin_token.is_some() || CONFIG.ingest_path.is_some())
+        .then(|| Arc::new(Admin::open(&keyspace)));
+    if let (Some(admin), Some(dir)) = (&admin, &CONFIG.ingest_path) {
+        let ids = Ids::open(&keyspace);
+        // Mapping the cases of an older database reads every full text.
+        if !ids.needs_bootstrap() || db.cases.is_empty().unwrap() {
+            if ids.needs_bootstrap() {
+                ids.bootstrap(&db);
+            }
+            watch_ingest(admin.clone(), db.clone(), ids, dir);
+        } else {
+            error!("not watching {dir}: run `migrate` first to map the ids of the stored cases");
+        }
+    }
 
     #[cfg(feature = "vsearch")]
     let qclient = Qdrant::from_url(CONFIG.qdrant_grpc.as_str())
@@ -40,7 +63,9 @@ async fn main() {
 
     let app_state = AppState {
         db,
+        redirects,
         searcher,
+        admin,
         #[cfg(feature = "vsearch")]
         qclient,
     };
@@ -53,13 +78,30 @@ async fn main() {
                 Duration::from_secs(10),
             ));
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(search))
         .route("/case/{id}", get(case))
+        .route("/case_no/{case_no}", get(case_no))
+        .route("/doc/{doc_id}", get(doc))
+        .route("/api/search", get(api_search))
+        .route("/api/case/{id}", get(api_case))
         .route("/style.css", get(style))
-        .route("/help.txt", get(help))
-        .layer(middleware_stack)
-        .with_state(app_state);
+        .route("/help.txt", get(help));
+    if CONFIG.admin_token.is_some() {
+        app = app.route(
+            "/admin/case/{id}",
+            put(admin_update_case).delete(admin_delete_case),
+        );
+    }
+    let mut app = app.layer(middleware_stack);
+    // Added after the timeout, uploads may take longer.
+    if CONFIG.admin_token.is_some() && CONFIG.ingest_path.is_some() {
+        app = app.route(
+            "/admin/ingest",
+            post(admin_ingest).layer(DefaultBodyLimit::disable()),
+        );
+    }
+    let app = app.with_state(app_state);
 
     info!("listening on http://{}", addr);
     let listener = TcpListener::bind(addr).await.unwrap();
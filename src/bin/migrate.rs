@@ -0,0 +1,136 @@
+//! Rewrites the cases of a database converted by an earlier version in the
+//! current record format, in place, moving their full text out of `cases` and
+//! parsing it into paragraphs.
+//! Cases already in it are skipped, so an interrupted run can simply be
+//! started again. It also maps the ids of the stored cases for the live
+//! ingestion, if no `convert` has yet.
+//!
+//! Everything reads the older format too, so migrating is optional. The
+//! database is opened by a single process, so stop the server first: it would
+//! not see the rewritten records, nor a dictionary trained after it started.
+//!
+//! `migrate config.toml compress` then trains a zstd dictionary on a sample of
+//! the full texts, unless there is one already, and compresses every text with
+//! it. Later conversions compress new texts with the same dictionary.
+
+use cases::{CONFIG, Case, Ids, Store};
+use fjall::{Config, Keyspace, PersistMode};
+use tracing::info;
+use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt};
+
+#[cfg(not(target_os = "windows"))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+/// Cases rewritten per write batch.
+const BATCH_SIZE: usize = 10240;
+
+/// Texts the dictionary is trained on, spread over the whole database.
+const DICT_SAMPLES: usize = 20_000;
+
+/// Size of the trained dictionary, in bytes.
+const DICT_SIZE: usize = 256 * 1024;
+
+fn main() {
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::new("info,fjall=warn"))
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let time = std::time::Instant::now();
+    let keyspace = Config::new(&CONFIG.db)
+        .max_write_buffer_size(256_000_000)
+        .open()
+        .unwrap();
+    let db = Store::open(&keyspace);
+
+    let mut count = 0;
+    let mut migrated = 0;
+    let mut batch = keyspace.batch();
+    for i in db.cases.iter() {
+        let (k, v) = i.unwrap();
+        count += 1;
+        if Case::is_current(&v) {
+            continue;
+        }
+        let id = u32::from_be_bytes(k[..].try_into().unwrap());
+        db.insert(&mut batch, id, db.decode(&k, &v, true));
+        migrated += 1;
+        if migrated % BATCH_SIZE == 0 {
+            std::mem::replace(&mut batch, keyspace.batch())
+                .commit()
+                .unwrap();
+            info!("migrated {id}, time: {}", time.elapsed().as_secs());
+        }
+    }
+    batch.commit().unwrap();
+    keyspace.persist(PersistMode::SyncAll).unwrap();
+    info!(
+        "{migrated} of {count} cases rewritten, {}",
+        time.elapsed().as_secs()
+    );
+
+    let ids = Ids::open(&keyspace);
+    if ids.needs_bootstrap() {
+        ids.bootstrap(&db);
+    }
+
+    if std::env::args().nth(2).as_deref() == Some("compress") {
+        compress(&keyspace, db);
+    }
+    info!("Done, {}", time.elapsed().as_secs());
+}
+
+/// Compresses the texts not compressed with the active dictionary yet,
+/// training one first if there is none.
+fn compress(keyspace: &Keyspace, db: Store) {
+    let db = if db.has_dictionary() {
+        db
+    } else {
+        let dict = train(&db);
+        let id = db.add_dictionary(&dict);
+        info!("trained dictionary {id}, {} bytes", dict.len());
+        Store::open(keyspace)
+    };
+
+    let mut compressed = 0;
+    let (mut before, mut after) = (0u64, 0u64);
+    let mut batch = keyspace.batch();
+    for i in db.texts.iter() {
+        let (k, v) = i.unwrap();
+        if db.is_compressed(&v) {
+            continue;
+        }
+        let text = db.compress(db.decompress(&v));
+        before += v.len() as u64;
+        after += text.len() as u64;
+        batch.insert(&db.texts, k.clone(), text);
+        compressed += 1;
+        if compressed % BATCH_SIZE == 0 {
+            std::mem::replace(&mut batch, keyspace.batch())
+                .commit()
+                .unwrap();
+            info!(
+                "compressed {}, {before} -> {after} bytes",
+                u32::from_be_bytes(k[..].try_into().unwrap())
+            );
+        }
+    }
+    batch.commit().unwrap();
+    keyspace.persist(PersistMode::SyncAll).unwrap();
+    info!("{compressed} texts compressed, {before} -> {after} bytes");
+}
+
+/// A zstd dictionary trained on [`DICT_SAMPLES`] texts taken at even steps.
+fn train(db: &Store) -> Vec<u8> {
+    let step = (db.texts.approximate_len() / DICT_SAMPLES).max(1);
+    let samples: Vec<Vec<u8>> = db
+        .texts
+        .iter()
+        .step_by(step)
+        .take(DICT_SAMPLES)
+        .map(|i| db.decompress(&i.unwrap().1))
+        .collect();
+    info!("training dictionary on {} texts", samples.len());
+    zstd::dict::from_samples(&samples, DICT_SIZE).unwrap()
+}
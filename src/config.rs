@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{fs::read_to_string, sync::LazyLock};
+use std::{collections::BTreeMap, fs::read_to_string, sync::LazyLock};
 
 pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::load_config);
 
@@ -12,6 +12,18 @@ pub struct Config {
     pub raw_data_path: Option<String>,
     pub export_limit: Option<usize>,
     pub max_results: Option<usize>,
+    /// Threads preparing documents in `index`, defaults to the number of CPUs.
+    pub index_threads: Option<usize>,
+    /// Memory budget of the tantivy writer in `index`, in MB.
+    pub index_memory_mb: Option<usize>,
+    /// Source column to read each `Case` field from in `convert`, by field name.
+    pub columns: Option<BTreeMap<String, String>>,
+    /// CSV file the rows `convert` cannot read are appended to, defaults to `rejects.csv`.
+    pub rejects_path: Option<String>,
+    /// Bearer token of the `/admin` endpoints, which are only served when it is set.
+    pub admin_token: Option<String>,
+    /// Directory the server ingests dropped source files from, see `ingest`.
+    pub ingest_path: Option<String>,
     #[cfg(feature = "vsearch")]
     pub qdrant_grpc: String,
     #[cfg(feature = "vsearch")]
@@ -28,4 +40,8 @@ impl Config {
         let config: Config = basic_toml::from_str(&read_to_string(cfg_file).unwrap()).unwrap();
         config
     }
+
+    pub fn rejects_path(&self) -> &str {
+        self.rejects_path.as_deref().unwrap_or("rejects.csv")
+    }
 }
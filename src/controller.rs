@@ -3,16 +3,17 @@ use axum::{
     body::Body,
     extract::{Path, Query, State},
     http::{Response, StatusCode, header},
-    response::{Html, IntoResponse},
+    response::{Html, IntoResponse, Redirect},
 };
-use bincode::config::standard;
-use indexmap::IndexSet;
-use serde::Deserialize;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
 use std::sync::LazyLock;
 use tantivy::{
-    DocAddress, Score, TantivyDocument,
-    collector::{Count, TopDocs},
-    schema::Value,
+    Order, Score,
+    collector::{Collector, Count, FacetCollector, FacetCounts},
+    query::{AllQuery, BooleanQuery, Occur, Query as SearchQuery},
+    schema::Facet,
+    snippet::SnippetGenerator,
 };
 use tracing::info;
 
@@ -28,10 +29,17 @@ use qdrant_client::{
     qdrant::{RecommendPointsBuilder, SearchPointsBuilder, point_id::PointIdOptions},
 };
 
-use crate::{AppState, CONFIG, Case, remove_html_tags};
+use crate::{
+    AppState, CONFIG, Case, FACETS, escape_html,
+    export::Format,
+    normalize_case_no, parse_case_no,
+    tantivy::{Cursor, PREVIEW_CHARS, SearchAfter},
+};
 
 static EXPORT_LIMIT: LazyLock<usize> = LazyLock::new(|| CONFIG.export_limit.unwrap_or(10000));
 static MAX_RESULTS: LazyLock<usize> = LazyLock::new(|| CONFIG.max_results.unwrap_or(50000));
+#[cfg(feature = "vsearch")]
+const VSEARCH_OVERFETCH: usize = 5;
 
 #[derive(Template)]
 #[template(path = "case.html", escape = "none")]
@@ -46,7 +54,7 @@ pub struct CasePage {
 #[derive(Debug, Deserialize)]
 pub struct QueryCase {
     #[cfg(feature = "vsearch")]
-    with_similar: Option<bool>,
+    pub(crate) with_similar: Option<bool>,
 }
 
 pub async fn case(
@@ -55,56 +63,20 @@ pub async fn case(
     Path(id): Path<u32>,
 ) -> impl IntoResponse {
     info!("id: {}", id);
-    if let Some(v) = state.db.get(id.to_be_bytes()).unwrap() {
-        let (mut case, _): (Case, _) = bincode::decode_from_slice(&v, standard()).unwrap();
-        case.parties = case.parties.trim_matches(',').replace(',', "，");
-        case.legal_basis = case.legal_basis.trim_matches(',').replace(',', "，");
-        if let Some(pos) = case.full_text.find(r#"c_header"#)
-            && let Some(start) = case.full_text[..pos].rfind("<")
-        {
-            case.full_text = case.full_text[start..].to_owned();
-            case.full_text = mark_c_right_strict(&case.full_text);
-        }
-
-        #[allow(unused_mut)]
-        let mut enable_similar = false;
-        #[allow(unused_mut)]
-        let mut similar_cases = Vec::new();
-        #[cfg(feature = "vsearch")]
-        {
-            let mut with_similar = params.with_similar.unwrap_or(false);
-            if !case.case_type.starts_with("刑事") {
-                with_similar = false;
-            } else {
-                enable_similar = true;
-            }
-
-            if with_similar {
-                let now = std::time::Instant::now();
-                let similar_ids = similar(id, &state.qclient).await;
-
-                for sid in similar_ids {
-                    if let Some(v) = state.db.get(sid.to_be_bytes()).unwrap() {
-                        let (scase, _): (Case, _) =
-                            bincode::decode_from_slice(&v, standard()).unwrap();
-                        similar_cases.push((sid, scase.case_name, scase.case_id));
-                    }
-                }
-                let elapsed = now.elapsed().as_secs_f32();
-                info!(
-                    "similar id: {}, found {} similar cases, elapsed: {}s",
-                    id,
-                    similar_cases.len(),
-                    elapsed
-                );
-            }
-        }
+    if let Some(canonical) = canonical_id(&state, id) {
+        return Redirect::permanent(&format!("/case/{canonical}")).into_response();
+    }
+    #[cfg(feature = "vsearch")]
+    let with_similar = params.with_similar.unwrap_or(false);
+    #[cfg(not(feature = "vsearch"))]
+    let with_similar = false;
 
+    if let Some(detail) = case_detail(id, &state, with_similar).await {
         let case = CasePage {
             id,
-            case,
-            enable_similar,
-            similar_cases,
+            case: detail.case,
+            enable_similar: detail.enable_similar,
+            similar_cases: detail.similar_cases,
         };
         into_response(&case)
     } else {
@@ -112,12 +84,283 @@ pub async fn case(
     }
 }
 
+/// The id `id` was merged into, if it is a legacy duplicate.
+pub(crate) fn canonical_id(state: &AppState, id: u32) -> Option<u32> {
+    let v = state.redirects.get(id.to_be_bytes()).unwrap()?;
+    Some(u32::from_be_bytes(v[..].try_into().unwrap()))
+}
+
+/// `/case_no/{case_no}`, permalink by case number (案号).
+pub async fn case_no(State(state): State<AppState>, Path(case_no): Path<String>) -> Response<Body> {
+    let case_no = normalize_case_no(&case_no);
+    exact_match(&state, "case_id_raw", &case_no)
+        .unwrap_or_else(|| (StatusCode::NOT_FOUND, "Not found").into_response())
+}
+
+/// `/doc/{doc_id}`, permalink by the wenshu docId.
+pub async fn doc(State(state): State<AppState>, Path(doc_id): Path<String>) -> Response<Body> {
+    exact_match(&state, "doc_id", doc_id.trim())
+        .unwrap_or_else(|| (StatusCode::NOT_FOUND, "Not found").into_response())
+}
+
+/// Redirects to the case whose raw `field` is `value`, or to a search listing
+/// the exact matches when there are several. `None` when nothing matches.
+fn exact_match(state: &AppState, field: &str, value: &str) -> Option<Response<Body>> {
+    let mut ids = Vec::new();
+    for id in state.searcher.lookup(field, value).unwrap() {
+        let id = canonical_id(state, id).unwrap_or(id);
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    info!("exact {field} {value}: {} found", ids.len());
+    match ids[..] {
+        [] => None,
+        [id] => Some(Redirect::to(&format!("/case/{id}")).into_response()),
+        _ => {
+            let query =
+                serde_urlencoded::to_string([("search", format!("{field}:\"{value}\""))]).unwrap();
+            Some(Redirect::to(&format!("/?{query}")).into_response())
+        }
+    }
+}
+
+pub(crate) struct CaseDetail {
+    pub(crate) case: Case,
+    pub(crate) enable_similar: bool,
+    pub(crate) similar_cases: Vec<(u32, String, String)>,
+}
+
+/// Loads a case and, with the `vsearch` feature, its similar cases.
+#[allow(unused_variables)]
+pub(crate) async fn case_detail(
+    id: u32,
+    state: &AppState,
+    with_similar: bool,
+) -> Option<CaseDetail> {
+    let case = state.db.case(id)?;
+
+    #[allow(unused_mut)]
+    let mut enable_similar = false;
+    #[allow(unused_mut)]
+    let mut similar_cases = Vec::new();
+    #[cfg(feature = "vsearch")]
+    {
+        let mut with_similar = with_similar;
+        if !case.case_type.starts_with("刑事") {
+            with_similar = false;
+        } else {
+            enable_similar = true;
+        }
+
+        if with_similar {
+            let now = std::time::Instant::now();
+            let similar_ids = similar(id, &state.qclient).await;
+
+            for sid in similar_ids {
+                if let Some(scase) = state.db.meta(sid) {
+                    similar_cases.push((sid, scase.case_name, scase.case_id));
+                }
+            }
+            let elapsed = now.elapsed().as_secs_f32();
+            info!(
+                "similar id: {}, found {} similar cases, elapsed: {}s",
+                id,
+                similar_cases.len(),
+                elapsed
+            );
+        }
+    }
+
+    Some(CaseDetail {
+        case,
+        enable_similar,
+        similar_cases,
+    })
+}
+
 #[derive(Debug, Deserialize)]
 pub struct QuerySearch {
     search: Option<String>,
     offset: Option<usize>,
-    export: Option<bool>,
+    pub(crate) export: Option<bool>,
+    format: Option<String>,
     search_type: Option<String>,
+    sort: Option<String>,
+    cursor: Option<String>,
+    court: Option<String>,
+    region: Option<String>,
+    case_type: Option<String>,
+    procedure: Option<String>,
+    cause: Option<String>,
+    date_from: Option<String>,
+    date_to: Option<String>,
+}
+
+impl QuerySearch {
+    fn filters(&mut self) -> Filters {
+        let take = |v: &mut Option<String>| {
+            v.take()
+                .map(|v| v.trim().to_owned())
+                .filter(|v| !v.is_empty())
+        };
+        Filters {
+            court: take(&mut self.court),
+            region: take(&mut self.region),
+            case_type: take(&mut self.case_type),
+            procedure: take(&mut self.procedure),
+            cause: take(&mut self.cause),
+            date_from: take(&mut self.date_from),
+            date_to: take(&mut self.date_to),
+        }
+    }
+}
+
+/// Structured filters, combined with the free-text query as must-clauses.
+/// Dates are `2020`, `2020-03` or `2020-03-15` and bound the judgment date.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Filters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    court: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    case_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    procedure: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cause: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_to: Option<String>,
+}
+
+impl Filters {
+    fn is_empty(&self) -> bool {
+        self.text_filters().next().is_none() && self.date_from.is_none() && self.date_to.is_none()
+    }
+
+    fn text_filters(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("court", &self.court),
+            ("region", &self.region),
+            ("case_type", &self.case_type),
+            ("procedure", &self.procedure),
+            ("cause", &self.cause),
+        ]
+        .into_iter()
+        .filter_map(|(field, value)| Some((field, value.as_deref()?)))
+    }
+
+    fn date_range(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.date_from.as_deref().and_then(|d| date_bound(d, false)),
+            self.date_to.as_deref().and_then(|d| date_bound(d, true)),
+        )
+    }
+
+    /// `&court=...&date_from=...` to append to links.
+    pub fn to_query(&self) -> String {
+        let query = serde_urlencoded::to_string(self).unwrap_or_default();
+        if query.is_empty() {
+            query
+        } else {
+            format!("&{query}")
+        }
+    }
+
+    fn queries(&self, searcher: &crate::tantivy::Searcher) -> Vec<(Occur, Box<dyn SearchQuery>)> {
+        let mut queries = Vec::new();
+        for (field, value) in self.text_filters() {
+            queries.push((Occur::Must, searcher.text_filter(field, value).unwrap()));
+        }
+        let (from, to) = self.date_range();
+        if from.is_some() || to.is_some() {
+            queries.push((
+                Occur::Must,
+                searcher.range_filter("judgment_ymd", from, to).unwrap(),
+            ));
+        }
+        queries
+    }
+
+    /// Approximates [`Filters::queries`] on a stored case, for semantic search.
+    #[cfg(feature = "vsearch")]
+    fn matches(&self, case: &Case) -> bool {
+        let text_match = self.text_filters().all(|(field, value)| {
+            let text = match field {
+                "court" => &case.court,
+                "region" => &case.region,
+                "case_type" => &case.case_type,
+                "procedure" => &case.procedure,
+                _ => &case.cause,
+            };
+            value.split_whitespace().all(|v| text.contains(v))
+        });
+        let (from, to) = self.date_range();
+        let date = case.judgment_date.map(crate::date_number);
+        let date_match = match (from, to) {
+            (None, None) => true,
+            _ => date.is_some_and(|d| from.is_none_or(|f| d >= f) && to.is_none_or(|t| d <= t)),
+        };
+        text_match && date_match
+    }
+}
+
+/// `2020`, `2020-03` or `2020-03-15` as a `yyyymmdd` bound, filling in the
+/// start or the end of the period.
+fn date_bound(date: &str, end: bool) -> Option<u64> {
+    let mut parts = date.trim().splitn(3, '-');
+    let year: u64 = parts.next()?.parse().ok()?;
+    let month: Option<u64> = parts.next().and_then(|m| m.parse().ok());
+    let day: Option<u64> = parts.next().and_then(|d| d.parse().ok());
+    let (default_month, default_day) = if end { (12, 31) } else { (1, 1) };
+    Some(year * 10000 + month.unwrap_or(default_month) * 100 + day.unwrap_or(default_day))
+}
+
+/// Result order of keyword search.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sort {
+    #[default]
+    Relevance,
+    JudgmentDateDesc,
+    JudgmentDateAsc,
+    PublicDateDesc,
+    PublicDateAsc,
+}
+
+impl Sort {
+    fn parse(s: Option<&str>) -> Sort {
+        match s {
+            Some("judgment_date_desc") => Sort::JudgmentDateDesc,
+            Some("judgment_date_asc") => Sort::JudgmentDateAsc,
+            Some("public_date_desc") => Sort::PublicDateDesc,
+            Some("public_date_asc") => Sort::PublicDateAsc,
+            _ => Sort::Relevance,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Relevance => "relevance",
+            Sort::JudgmentDateDesc => "judgment_date_desc",
+            Sort::JudgmentDateAsc => "judgment_date_asc",
+            Sort::PublicDateDesc => "public_date_desc",
+            Sort::PublicDateAsc => "public_date_asc",
+        }
+    }
+
+    /// The fast field and order to sort by, `None` for relevance.
+    fn fast_field(&self) -> Option<(&'static str, Order)> {
+        match self {
+            Sort::Relevance => None,
+            Sort::JudgmentDateDesc => Some(("judgment_ymd", Order::Desc)),
+            Sort::JudgmentDateAsc => Some(("judgment_ymd", Order::Asc)),
+            Sort::PublicDateDesc => Some(("public_ymd", Order::Desc)),
+            Sort::PublicDateAsc => Some(("public_ymd", Order::Asc)),
+        }
+    }
 }
 
 #[derive(Template)]
@@ -127,8 +370,35 @@ pub struct SearchPage {
     offset: usize,
     total: usize,
     search_type: String,
+    sort: Sort,
+    filters: Filters,
+    cursor: Option<String>,
+    next_cursor: Option<String>,
+    /// Whether there is a next page. Keyword search pages can hold fewer
+    /// cases once duplicates are merged, so they go by the cursor.
+    has_next: bool,
+    /// Whether the previous page can be reached by offset, which stops at
+    /// `max_results`; pages after it are only reached by cursor.
+    has_prev: bool,
     enable_vsearch: bool,
     cases: Vec<(u32, String, Case)>,
+    facets: Vec<FacetGroup>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FacetGroup {
+    name: &'static str,
+    label: &'static str,
+    values: Vec<FacetValue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FacetValue {
+    value: String,
+    count: u64,
+    /// Link to the search narrowed to this value, for the HTML sidebar.
+    #[serde(skip)]
+    href: Option<String>,
 }
 
 #[cfg(feature = "vsearch")]
@@ -142,74 +412,127 @@ static MODEL: LazyLock<Mutex<TextEmbedding>> = LazyLock::new(|| {
     Mutex::new(model)
 });
 
-pub async fn search(
-    Query(input): Query<QuerySearch>,
-    State(state): State<AppState>,
-) -> impl IntoResponse {
+pub(crate) struct SearchOutput {
+    pub(crate) search: String,
+    pub(crate) search_type: String,
+    pub(crate) offset: usize,
+    pub(crate) limit: usize,
+    pub(crate) total: usize,
+    pub(crate) export: bool,
+    pub(crate) sort: Sort,
+    pub(crate) filters: Filters,
+    /// Cursor this page started after, if any.
+    pub(crate) cursor: Option<String>,
+    /// Cursor of the last hit, set when more keyword hits may follow.
+    pub(crate) next_cursor: Option<String>,
+    /// Hits in display order, with their score when sorted by relevance.
+    /// Duplicates are collapsed into their canonical case.
+    pub(crate) hits: IndexMap<u32, Option<Score>>,
+    pub(crate) facets: Vec<FacetGroup>,
+    pub(crate) snippets: Option<SnippetGenerator>,
+}
+
+pub(crate) async fn search_ids(mut input: QuerySearch, state: &AppState) -> SearchOutput {
+    let cursor = input.cursor.take().filter(|c| Cursor::decode(c).is_some());
+    let after = cursor.as_deref().and_then(Cursor::decode);
     let mut offset = input.offset.unwrap_or_default();
-    if offset > *MAX_RESULTS {
+    // A cursor pages without skipping hits, so deep pages are not capped.
+    if after.is_none() && offset > *MAX_RESULTS {
         offset = *MAX_RESULTS
     }
+    let filters = input.filters();
     let search = input.search.unwrap_or_default();
     let export = input.export.unwrap_or_default();
-    let search_type =
-        if cfg!(feature = "vsearch") && input.search_type.as_deref() == Some("vsearch") {
-            "vsearch".to_owned()
-        } else {
-            "keyword".to_owned()
-        };
+    let search_type = if cfg!(feature = "vsearch")
+        && input.search_type.as_deref() == Some("vsearch")
+        && !search.trim().is_empty()
+    {
+        "vsearch".to_owned()
+    } else {
+        "keyword".to_owned()
+    };
     let limit = if export { *EXPORT_LIMIT } else { 20 };
-    let mut ids: IndexSet<u32> = IndexSet::with_capacity(20);
+    let sort = Sort::parse(input.sort.as_deref());
+    let mut hits: IndexMap<u32, Option<Score>> = IndexMap::with_capacity(20);
     let mut total = 0;
-    if !search.trim().is_empty() {
+    let mut facets = Vec::new();
+    let mut snippets = None;
+    let mut next_cursor = None;
+    if !search.trim().is_empty() || !filters.is_empty() {
         let now = std::time::Instant::now();
         let search = fast2s::convert(&search);
         if search_type == "keyword" {
-            let (query, _) = state.searcher.query_parser.parse_query_lenient(&search);
+            let query: Box<dyn SearchQuery> = if search.trim().is_empty() {
+                Box::new(AllQuery)
+            } else {
+                state.searcher.query_parser.parse_query_lenient(&search).0
+            };
+            let mut clauses = filters.queries(&state.searcher);
+            let query: Box<dyn SearchQuery> = if clauses.is_empty() {
+                query
+            } else {
+                clauses.push((Occur::Must, query));
+                Box::new(BooleanQuery::new(clauses))
+            };
             let searcher = state.searcher.reader.searcher();
-            total = searcher.search(&query, &Count).unwrap();
-
-            let top_docs: Vec<(Score, DocAddress)> = searcher
-                .search(
-                    &query,
-                    &TopDocs::with_limit(limit)
-                        .and_offset(offset)
-                        .order_by_score(),
-                )
-                .unwrap_or_default();
-
-            for (_score, doc_address) in top_docs {
-                if let Some(id) = searcher
-                    .doc::<TantivyDocument>(doc_address)
-                    .unwrap()
-                    .get_first(state.searcher.id)
-                    .unwrap()
-                    .as_u64()
-                {
-                    ids.insert(id as u32);
+            if export {
+                total = searcher.search(&query, &Count).unwrap();
+            } else {
+                let mut facet_collector = FacetCollector::for_field("facet");
+                for (name, _) in FACETS {
+                    facet_collector.add_facet(Facet::from_path([name]));
                 }
+                let (count, facet_counts) =
+                    searcher.search(&query, &(Count, facet_collector)).unwrap();
+                total = count;
+                facets = facet_groups(&facet_counts);
+                snippets = state.searcher.snippet_generator(&searcher, &query).ok();
+            }
+
+            let collector = SearchAfter {
+                limit,
+                offset: if after.is_some() { 0 } else { offset },
+                after,
+                sort_by: sort.fast_field(),
+            };
+            let top_docs = searcher.search(&query, &collector).unwrap_or_default();
+            if top_docs.len() >= limit {
+                next_cursor = top_docs.last().map(|(cursor, _)| cursor.encode());
+            }
+            for (cursor, score) in top_docs {
+                let score = collector.requires_scoring().then_some(score);
+                let id = canonical_id(state, cursor.id).unwrap_or(cursor.id);
+                hits.entry(id).or_insert(score);
             }
         } else {
             #[cfg(feature = "vsearch")]
             if search_type == "vsearch" {
                 {
                     let query_vec = MODEL.lock().unwrap().embed(vec![&search], None).unwrap();
-                    let client = state.qclient;
+                    let client = &state.qclient;
                     let search_limit = limit + offset;
                     total = search_limit;
+                    // Qdrant has no case metadata, so filtered searches over-fetch
+                    // from the top and check the filters on the stored cases.
+                    let (fetch_limit, fetch_offset) = if filters.is_empty() {
+                        (limit, offset)
+                    } else {
+                        (search_limit * VSEARCH_OVERFETCH, 0)
+                    };
                     if let Ok(search_result) = client
                         .search_points(
                             SearchPointsBuilder::new(
                                 &CONFIG.collection_name,
                                 query_vec.into_iter().next().unwrap(),
-                                search_limit as u64,
+                                fetch_limit as u64,
                             )
                             .with_payload(false)
-                            .limit(limit as u64)
-                            .offset(offset as u64),
+                            .limit(fetch_limit as u64)
+                            .offset(fetch_offset as u64),
                         )
                         .await
                     {
+                        let mut matched = 0;
                         for point in &search_result.result {
                             let id = point
                                 .id
@@ -219,7 +542,24 @@ pub async fn search(
                                 .as_ref()
                                 .unwrap();
                             if let PointIdOptions::Num(id) = id {
-                                ids.insert(*id as u32);
+                                let id = *id as u32;
+                                if !filters.is_empty() {
+                                    let Some(case) = state.db.meta(id) else {
+                                        continue;
+                                    };
+                                    if !filters.matches(&case) {
+                                        continue;
+                                    }
+                                    matched += 1;
+                                    if matched <= offset {
+                                        continue;
+                                    }
+                                    if hits.len() >= limit {
+                                        break;
+                                    }
+                                }
+                                let id = canonical_id(state, id).unwrap_or(id);
+                                hits.entry(id).or_insert(Some(point.score));
                             }
                         }
                     } else {
@@ -232,85 +572,218 @@ pub async fn search(
         let elapsed = now.elapsed().as_secs_f32();
         if export {
             info!(
-                "export {search_type} {search}, total:{total}, offset: {offset}, limit: {limit}, elapsed: {elapsed}s"
+                "export {search_type} {search} {filters:?}, total:{total}, offset: {offset}, limit: {limit}, elapsed: {elapsed}s"
             );
         } else {
             info!(
-                "search {search_type} {search}, total:{total}, offset: {offset}, limit: {limit}, elapsed: {elapsed}s "
+                "search {search_type} {search} {filters:?}, total:{total}, offset: {offset}, limit: {limit}, elapsed: {elapsed}s "
             );
         }
     }
 
-    let mut cases = Vec::with_capacity(ids.len());
-    for id in ids {
-        if let Some(v) = state.db.get(id.to_be_bytes()).unwrap() {
-            let (case, _): (Case, _) = bincode::decode_from_slice(&v, standard()).unwrap();
-            let preview = remove_html_tags(&case.full_text)
-                .chars()
-                .take(240)
+    SearchOutput {
+        search,
+        search_type,
+        offset,
+        limit,
+        total,
+        export,
+        sort,
+        filters,
+        cursor,
+        next_cursor,
+        hits,
+        facets,
+        snippets,
+    }
+}
+
+/// Link narrowing the current search to a facet value. Court levels below the
+/// intermediate courts have no matching court filter and get no link.
+fn facet_href(
+    search: &str,
+    search_type: &str,
+    sort: Sort,
+    filters: &Filters,
+    name: &str,
+    value: &str,
+) -> Option<String> {
+    let mut filters = filters.clone();
+    match name {
+        "court" => filters.court = Some(value.to_owned()),
+        "region" => filters.region = Some(value.to_owned()),
+        "court_level" if value != "基层人民法院" && value != "专门法院" => {
+            filters.court = Some(value.to_owned())
+        }
+        "case_type" => filters.case_type = Some(value.to_owned()),
+        "procedure" => filters.procedure = Some(value.to_owned()),
+        "year" => {
+            filters.date_from = Some(value.to_owned());
+            filters.date_to = Some(value.to_owned());
+        }
+        _ => return None,
+    }
+    let query = serde_urlencoded::to_string([
+        ("search", search),
+        ("search_type", search_type),
+        ("sort", sort.as_str()),
+    ])
+    .unwrap_or_default();
+    Some(format!("/?{query}{}", filters.to_query()))
+}
+
+/// Top values of each facet dimension; years are listed newest first.
+fn facet_groups(facet_counts: &FacetCounts) -> Vec<FacetGroup> {
+    FACETS
+        .iter()
+        .map(|&(name, label)| {
+            let parent = Facet::from_path([name]);
+            let values: Vec<(&Facet, u64)> = if name == "year" {
+                let mut values: Vec<_> = facet_counts.get(parent).collect();
+                values.reverse();
+                values
+            } else {
+                facet_counts.top_k(parent, 10)
+            };
+            let values = values
+                .into_iter()
+                .filter_map(|(facet, count)| {
+                    let value = facet.to_path().last()?.to_string();
+                    Some(FacetValue {
+                        value,
+                        count,
+                        href: None,
+                    })
+                })
                 .collect();
+            FacetGroup {
+                name,
+                label,
+                values,
+            }
+        })
+        .collect()
+}
+
+/// Loads the cases without their full text, with an HTML preview: the best
+/// matching passage with the query terms in `<mark>`, or the beginning of the
+/// judgment. The passage is looked for in the stored preview text, then in the
+/// full text when the terms only occur further on.
+pub(crate) fn load_cases<'a>(
+    state: &AppState,
+    ids: impl IntoIterator<Item = &'a u32>,
+    snippets: Option<&SnippetGenerator>,
+) -> Vec<(u32, String, Case)> {
+    let ids = ids.into_iter();
+    let mut cases = Vec::with_capacity(ids.size_hint().0);
+    for &id in ids {
+        if let Some(case) = state.db.meta(id) {
+            let text = state.db.preview(id).unwrap_or_default();
+            let preview = snippets
+                .and_then(|generator| snippet(generator, &text))
+                .unwrap_or_else(|| head(&text));
             cases.push((id, preview, case));
         }
     }
+    cases
+}
 
-    // export to csv
-    if export {
-        let fname = format!("{search}_{total}_{limit}_{offset}.csv");
-        let body = Vec::new();
-        let mut wtr = csv::Writer::from_writer(body);
-        wtr.write_record([
-            "id",
-            "url",
-            "case_id",
-            "case_name",
-            "court",
-            "case_type",
-            "procedure",
-            "judgment_date",
-            "public_date",
-            "parties",
-            "cause",
-            "legal_basis",
-            "full_text",
-        ])
-        .unwrap();
-        for (id, _, case) in &cases {
-            wtr.write_record([
-                &id.to_string(),
-                &case.doc_id,
-                &case.case_id,
-                &case.case_name,
-                &case.court,
-                &case.case_type,
-                &case.procedure,
-                &case.judgment_date,
-                &case.public_date,
-                &case.parties.trim_matches(',').replace(',', "，"),
-                &case.cause,
-                &case.legal_basis.trim_matches(',').replace(',', "，"),
-                &case.full_text,
-            ])
-            .unwrap();
-        }
-        wtr.flush().unwrap();
+/// The passage of `text` best matching the query, as HTML.
+fn snippet(generator: &SnippetGenerator, text: &str) -> Option<String> {
+    let mut snippet = generator.snippet(text);
+    if snippet.is_empty() {
+        return None;
+    }
+    snippet.set_snippet_prefix_postfix("<mark>", "</mark>");
+    Some(snippet.to_html())
+}
 
+/// The beginning of `text`, as HTML.
+fn head(text: &str) -> String {
+    let mut preview = String::new();
+    let head: String = text.chars().take(PREVIEW_CHARS).collect();
+    escape_html(&head, &mut preview);
+    preview
+}
+
+pub async fn search(
+    Query(mut input): Query<QuerySearch>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let format = Format::parse(input.format.take().as_deref());
+    // A case number typed into the search box goes straight to the case.
+    if input.export != Some(true)
+        && input.offset.unwrap_or_default() == 0
+        && input.cursor.is_none()
+        && let Some(case_no) = input.search.as_deref().and_then(parse_case_no)
+        && let Some(response) = exact_match(&state, "case_id_raw", &case_no)
+    {
+        return response;
+    }
+    let SearchOutput {
+        search,
+        search_type,
+        offset,
+        limit,
+        total,
+        export,
+        sort,
+        filters,
+        cursor,
+        next_cursor,
+        hits,
+        mut facets,
+        snippets,
+    } = search_ids(input, &state).await;
+    if export {
+        let fname = format!("{search}_{total}_{limit}_{offset}.{}", format.extension());
         let headers = [
-            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (header::CONTENT_TYPE, format.content_type()),
             (
                 header::CONTENT_DISPOSITION,
                 &format!("attachment; filename={fname}"),
             ),
         ];
-        return (headers, wtr.into_inner().unwrap()).into_response();
+        let body = format.body(state.db.clone(), hits.into_keys().collect());
+        let mut response = (headers, body).into_response();
+        // Follow with `cursor=` to export the next batch.
+        if let Some(next_cursor) = next_cursor {
+            response.headers_mut().insert(
+                "x-next-cursor",
+                header::HeaderValue::from_str(&next_cursor).unwrap(),
+            );
+        }
+        return response;
     }
 
+    let cases = load_cases(&state, hits.keys(), snippets.as_ref());
+
+    for group in &mut facets {
+        for v in &mut group.values {
+            v.href = facet_href(&search, &search_type, sort, &filters, group.name, &v.value);
+        }
+    }
+
+    let has_next = if search_type == "keyword" {
+        next_cursor.is_some()
+    } else {
+        cases.len() >= 20
+    };
+    let has_prev = offset >= 20 && offset - 20 <= *MAX_RESULTS;
     let body = SearchPage {
         search,
         search_type,
+        sort,
+        filters,
+        cursor,
+        next_cursor,
+        has_next,
+        has_prev,
         offset,
         cases,
         total,
         enable_vsearch: cfg!(feature = "vsearch"),
+        facets,
     };
 
     into_response(&body)
@@ -366,68 +839,3 @@ pub async fn similar(id: u32, qclient: &Qdrant) -> Vec<u32> {
     }
     ids
 }
-
-fn mark_c_right_strict(html: &str) -> String {
-    let document = scraper::Html::parse_document(html);
-    let selector = scraper::Selector::parse("div").unwrap();
-
-    let start_keywords = ["审判员", "审判长", "执行长"];
-    let clerk_keyword = "书记员";
-
-    let mut started = false;
-    let mut in_clerk_block = false;
-
-    let mut output = String::new();
-
-    for div in document.select(&selector) {
-        let raw_text: String = div.text().collect::<Vec<_>>().join("");
-        let text = normalize_no_whitespace(&raw_text);
-
-        let is_start = start_keywords.iter().any(|k| text.starts_with(k));
-        let is_clerk = text.starts_with(clerk_keyword);
-
-        if !started && is_start {
-            started = true;
-        }
-
-        if started && is_clerk {
-            in_clerk_block = true;
-        }
-
-        let should_mark = started && (!in_clerk_block || is_clerk);
-
-        let mut div_html = div.html();
-
-        if should_mark {
-            let already_marked = div
-                .value()
-                .attr("class")
-                .map(|c| c.split_whitespace().any(|x| x == "c_right"))
-                .unwrap_or(false);
-
-            if !already_marked {
-                if div.value().attr("class").is_some() {
-                    div_html = div_html.replacen("class=\"", "class=\"c_right ", 1);
-                } else {
-                    div_html = div_html.replacen("<div", "<div class=\"c_right\"", 1);
-                }
-            }
-        }
-
-        output.push_str(&div_html);
-        output.push('\n');
-
-        if in_clerk_block && !is_clerk {
-            started = false;
-            in_clerk_block = false;
-        }
-    }
-
-    output
-}
-
-fn normalize_no_whitespace(s: &str) -> String {
-    s.chars()
-        .filter(|c| !c.is_whitespace() && *c != '　')
-        .collect()
-}
@@ -0,0 +1,334 @@
+use arrow::{
+    array::{ArrayBuilder, ArrayRef, RecordBatch, StringBuilder, UInt32Builder},
+    datatypes::{DataType, Field, Schema, SchemaRef},
+};
+use axum::body::{Body, Bytes};
+use futures_util::stream;
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use rust_xlsxwriter::{Format as CellFormat, Workbook};
+use serde::{Serialize, Serializer, ser::SerializeMap};
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    sync::Arc,
+};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::{Case, Store, format_date};
+
+const CHUNK_SIZE: usize = 256 * 1024;
+
+/// `io::Write` that forwards its output to a streaming response body.
+pub(crate) struct ChannelWriter {
+    tx: mpsc::Sender<io::Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            let chunk = Bytes::from(std::mem::replace(
+                &mut self.buf,
+                Vec::with_capacity(CHUNK_SIZE),
+            ));
+            self.tx
+                .blocking_send(Ok(chunk))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client disconnected"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs `write` on a blocking thread and streams what it writes as the body,
+/// so the first bytes are sent while the rest is still being produced.
+pub(crate) fn stream_body<F>(write: F) -> Body
+where
+    F: FnOnce(&mut ChannelWriter) -> io::Result<()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChannelWriter {
+            tx,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        };
+        if let Err(e) = write(&mut writer).and_then(|_| writer.flush()) {
+            warn!("export aborted: {e}");
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                let _ = writer.tx.blocking_send(Err(e));
+            }
+        }
+    });
+    Body::from_stream(stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+/// Column names shared by every export format, except CSV which keeps `url`
+/// for `doc_id`.
+const COLUMNS: [&str; 16] = [
+    "id",
+    "doc_id",
+    "case_id",
+    "case_name",
+    "court",
+    "region",
+    "case_type",
+    "case_type_code",
+    "source",
+    "procedure",
+    "judgment_date",
+    "public_date",
+    "parties",
+    "cause",
+    "legal_basis",
+    "full_text",
+];
+
+/// Excel rejects cells longer than this.
+const XLSX_CELL_CHARS: usize = 32767;
+
+/// Rows per Parquet row group, bounds the memory held before a flush.
+const PARQUET_ROWS: usize = 1024;
+
+/// The text columns of `case`, in [`COLUMNS`] order after `id`, with
+/// `parties` and `legal_basis` joined the way the case page shows them.
+pub(crate) fn fields(case: &Case) -> [Cow<'_, str>; 15] {
+    [
+        Cow::Borrowed(&case.doc_id),
+        Cow::Borrowed(&case.case_id),
+        Cow::Borrowed(&case.case_name),
+        Cow::Borrowed(&case.court),
+        Cow::Borrowed(&case.region),
+        Cow::Borrowed(&case.case_type),
+        Cow::Borrowed(&case.case_type_code),
+        Cow::Borrowed(&case.source),
+        Cow::Borrowed(&case.procedure),
+        Cow::Owned(format_date(case.judgment_date)),
+        Cow::Owned(format_date(case.public_date)),
+        Cow::Owned(case.parties.join("，")),
+        Cow::Borrowed(&case.cause),
+        Cow::Owned(case.legal_basis.join("，")),
+        Cow::Owned(case.full_text.to_html()),
+    ]
+}
+
+/// Arrow schema of the exported cases, used for Parquet output.
+pub fn arrow_schema() -> SchemaRef {
+    let mut fields = vec![Field::new(COLUMNS[0], DataType::UInt32, false)];
+    fields.extend(
+        COLUMNS[1..]
+            .iter()
+            .map(|name| Field::new(*name, DataType::Utf8, false)),
+    );
+    Arc::new(Schema::new(fields))
+}
+
+/// Accumulates cases into Arrow columns matching [`arrow_schema`].
+#[derive(Default)]
+pub struct CaseColumns {
+    id: UInt32Builder,
+    text: [StringBuilder; 15],
+}
+
+impl CaseColumns {
+    pub fn push(&mut self, id: u32, case: &Case) {
+        self.id.append_value(id);
+        for (builder, value) in self.text.iter_mut().zip(fields(case)) {
+            builder.append_value(value);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the rows pushed so far as a batch and starts over.
+    pub fn finish(&mut self) -> RecordBatch {
+        let mut columns = vec![Arc::new(self.id.finish()) as ArrayRef];
+        columns.extend(
+            self.text
+                .iter_mut()
+                .map(|builder| Arc::new(builder.finish()) as ArrayRef),
+        );
+        RecordBatch::try_new(arrow_schema(), columns).unwrap()
+    }
+}
+
+/// Export file format, chosen with `format=`; CSV when absent or unknown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Csv,
+    Jsonl,
+    Parquet,
+    Xlsx,
+}
+
+impl Format {
+    pub(crate) fn parse(s: Option<&str>) -> Self {
+        match s {
+            Some("jsonl") => Format::Jsonl,
+            Some("parquet") => Format::Parquet,
+            Some("xlsx") => Format::Xlsx,
+            _ => Format::Csv,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl",
+            Format::Parquet => "parquet",
+            Format::Xlsx => "xlsx",
+        }
+    }
+
+    pub(crate) fn content_type(self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Jsonl => "application/x-ndjson; charset=utf-8",
+            Format::Parquet => "application/vnd.apache.parquet",
+            Format::Xlsx => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        }
+    }
+
+    pub(crate) fn body(self, db: Store, ids: Vec<u32>) -> Body {
+        match self {
+            Format::Csv => csv(db, ids),
+            Format::Jsonl => jsonl(db, ids),
+            Format::Parquet => parquet(db, ids),
+            Format::Xlsx => xlsx(db, ids),
+        }
+    }
+}
+
+/// Reads the cases one by one from `db` and yields them in `ids` order.
+fn cases<'a>(db: &'a Store, ids: &'a [u32]) -> impl Iterator<Item = (u32, Case)> + 'a {
+    ids.iter()
+        .filter_map(move |&id| db.case(id).map(|case| (id, case)))
+}
+
+fn csv(db: Store, ids: Vec<u32>) -> Body {
+    stream_body(move |out| {
+        let mut wtr = csv::Writer::from_writer(out);
+        wtr.write_record([
+            "id",
+            "url",
+            "case_id",
+            "case_name",
+            "court",
+            "region",
+            "case_type",
+            "case_type_code",
+            "source",
+            "procedure",
+            "judgment_date",
+            "public_date",
+            "parties",
+            "cause",
+            "legal_basis",
+            "full_text",
+        ])?;
+        for (id, case) in cases(&db, &ids) {
+            let id = id.to_string();
+            let fields = fields(&case);
+            wtr.write_record(
+                std::iter::once(id.as_str()).chain(fields.iter().map(|f| f.as_ref())),
+            )?;
+        }
+        wtr.flush()
+    })
+}
+
+/// One case as a JSON object keyed by [`COLUMNS`].
+struct Row<'a>(u32, &'a Case);
+
+impl Serialize for Row<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(COLUMNS.len()))?;
+        map.serialize_entry(COLUMNS[0], &self.0)?;
+        for (name, value) in COLUMNS[1..].iter().zip(fields(self.1)) {
+            map.serialize_entry(name, &value)?;
+        }
+        map.end()
+    }
+}
+
+fn jsonl(db: Store, ids: Vec<u32>) -> Body {
+    stream_body(move |out| {
+        for (id, case) in cases(&db, &ids) {
+            serde_json::to_writer(&mut *out, &Row(id, &case))?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
+    })
+}
+
+fn parquet(db: Store, ids: Vec<u32>) -> Body {
+    stream_body(move |out| {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::LZ4)
+            .set_max_row_group_row_count(Some(PARQUET_ROWS))
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(out, arrow_schema(), Some(props)).map_err(io::Error::other)?;
+        let mut columns = CaseColumns::default();
+        for (id, case) in cases(&db, &ids) {
+            columns.push(id, &case);
+            if columns.len() >= PARQUET_ROWS {
+                writer.write(&columns.finish()).map_err(io::Error::other)?;
+            }
+        }
+        if !columns.is_empty() {
+            writer.write(&columns.finish()).map_err(io::Error::other)?;
+        }
+        writer.close().map_err(io::Error::other)?;
+        Ok(())
+    })
+}
+
+/// Spreadsheet with the full text as plain text, since Excel neither renders
+/// the HTML nor accepts cells over 32767 characters.
+fn xlsx(db: Store, ids: Vec<u32>) -> Body {
+    stream_body(move |out| {
+        let mut workbook = Workbook::new();
+        let bold = CellFormat::new().set_bold();
+        let sheet = workbook.add_worksheet_with_constant_memory();
+        for (col, name) in COLUMNS.iter().enumerate() {
+            sheet
+                .write_string_with_format(0, col as u16, *name, &bold)
+                .map_err(io::Error::other)?;
+        }
+        for (row, (id, case)) in cases(&db, &ids).enumerate() {
+            let row = row as u32 + 1;
+            let mut values = fields(&case);
+            values[values.len() - 1] = case
+                .full_text
+                .to_text()
+                .chars()
+                .take(XLSX_CELL_CHARS)
+                .collect();
+            sheet.write_number(row, 0, id).map_err(io::Error::other)?;
+            for (col, value) in values.into_iter().enumerate() {
+                sheet
+                    .write_string(row, col as u16 + 1, value)
+                    .map_err(io::Error::other)?;
+            }
+        }
+        // The rows wait in a temporary file in constant memory mode, and the
+        // zip is written as a stream, so the workbook is never held in memory.
+        workbook.save_to_writer(out).map_err(io::Error::other)
+    })
+}
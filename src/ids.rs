@@ -0,0 +1,347 @@
+//! Case ids: the persistent `doc_ids` mapping from the source key of a
+//! document to its id, shared by `convert` and the live ingestion of the
+//! server.
+
+use std::collections::HashMap;
+
+use bincode::config::standard;
+use fjall::{Batch, Keyspace, PartitionCreateOptions, PartitionHandle};
+use tracing::info;
+
+use crate::{Case, Store, source_key, text_hash};
+
+/// Reserved `doc_ids` key holding the next free id, no source key starts with NUL.
+const NEXT_ID_KEY: &[u8] = b"\0next_id";
+
+/// Staged cases mapped per write batch by [`Ids::resolve`].
+const RESOLVE_BATCH: usize = 10240;
+
+/// Assigns case ids through the persistent `doc_ids` mapping from the source
+/// key of a document to its id, so a document keeps its id whatever order the
+/// archives are read in, and new documents get fresh ids.
+///
+/// Every imported entry reserves one id per row, after the entries reserved
+/// before it, so a new database is numbered in source order whichever thread
+/// stores the rows. A row whose key is mapped already keeps its id and leaves
+/// its own unused. The new rows are staged in `pending` until
+/// [`Ids::resolve`] maps them in id order: a later copy of a document, by key
+/// or by full text, is then removed and recorded as an alias of the first.
+pub struct Ids {
+    keyspace: Keyspace,
+    doc_ids: PartitionHandle,
+    text_hashes: PartitionHandle,
+    /// Entry name → CRC-32, first id and row count of its reserved range.
+    ranges: PartitionHandle,
+    /// Staged id → full text hash and source key.
+    pending: PartitionHandle,
+}
+
+impl Ids {
+    pub fn open(keyspace: &Keyspace) -> Ids {
+        let open = |name| {
+            keyspace
+                .open_partition(name, PartitionCreateOptions::default())
+                .unwrap()
+        };
+        Ids {
+            keyspace: keyspace.clone(),
+            doc_ids: open("doc_ids"),
+            text_hashes: open("text_hashes"),
+            ranges: open("id_ranges"),
+            pending: open("pending"),
+        }
+    }
+
+    /// Whether the mappings are missing, for a new database or one converted
+    /// by an earlier version, see [`Ids::bootstrap`].
+    pub fn needs_bootstrap(&self) -> bool {
+        self.doc_ids.get(NEXT_ID_KEY).unwrap().is_none() || self.text_hashes.is_empty().unwrap()
+    }
+
+    /// Fills `text_hashes`, and `doc_ids` too if it is empty, from the stored
+    /// cases, reading every full text. Later copies of a document, by key or by
+    /// text, get a redirect to the first one.
+    pub fn bootstrap(&self, db: &Store) {
+        let map_keys = self.doc_ids.get(NEXT_ID_KEY).unwrap().is_none();
+        let redirects = self
+            .keyspace
+            .open_partition("redirects", PartitionCreateOptions::default())
+            .unwrap();
+        let mut count = 0;
+        for i in db.cases.iter() {
+            let (k, v) = i.unwrap();
+            count += 1;
+            if count % 100_000 == 0 {
+                info!(
+                    "mapping existing cases, {}",
+                    u32::from_be_bytes(k[..].try_into().unwrap())
+                );
+            }
+            if redirects.contains_key(&k).unwrap() {
+                continue;
+            }
+            let case = db.decode(&k, &v, true);
+            let key = source_key(&case);
+            if map_keys && let Some(canonical) = self.doc_ids.get(&key).unwrap() {
+                redirects.insert(k.clone(), canonical).unwrap();
+                continue;
+            }
+            if let Some(hash) = text_hash(&case.full_text) {
+                if let Some(canonical) = self.text_hashes.get(hash).unwrap() {
+                    redirects.insert(k.clone(), canonical.clone()).unwrap();
+                    self.doc_ids.insert(&key, canonical).unwrap();
+                    continue;
+                }
+                self.text_hashes.insert(hash, k.clone()).unwrap();
+            }
+            if map_keys {
+                self.doc_ids.insert(&key, k).unwrap();
+            }
+        }
+        if map_keys {
+            let next = match db.cases.last_key_value().unwrap() {
+                Some((k, _)) => u32::from_be_bytes(k[..].try_into().unwrap()) + 1,
+                None => 1,
+            };
+            self.doc_ids
+                .insert(NEXT_ID_KEY, next.to_be_bytes())
+                .unwrap();
+        }
+        if count > 0 {
+            info!(
+                "mapped {count} existing cases, {} redirects",
+                redirects.len().unwrap()
+            );
+        }
+    }
+
+    /// The id mapped to the source key `key`.
+    pub fn get(&self, key: &str) -> Option<u32> {
+        get_id(&self.doc_ids, key)
+    }
+
+    /// The first of the `rows` ids of the entry `name`: the range reserved for
+    /// it before if its checksum and row count are unchanged, as when resuming,
+    /// or a new one. Entries are reserved one at a time, in import order.
+    pub fn reserve(&self, name: &str, crc32: u32, rows: u32) -> u32 {
+        if let Some(v) = self.ranges.get(name).unwrap() {
+            let field = |i: usize| u32::from_be_bytes(v[i * 4..i * 4 + 4].try_into().unwrap());
+            if field(0) == crc32 && field(2) == rows {
+                return field(1);
+            }
+        }
+        let first = get_id(&self.doc_ids, NEXT_ID_KEY).unwrap();
+        let mut range = Vec::with_capacity(12);
+        for field in [crc32, first, rows] {
+            range.extend(field.to_be_bytes());
+        }
+        let mut batch = self.keyspace.batch();
+        batch.insert(&self.ranges, name, range);
+        batch.insert(&self.doc_ids, NEXT_ID_KEY, (first + rows).to_be_bytes());
+        batch.commit().unwrap();
+        first
+    }
+
+    /// Adds storing `case` as `id` to `batch`, staged for [`Ids::resolve`] and
+    /// for indexing.
+    pub fn stage(&self, batch: &mut Batch, db: &Store, id: u32, case: Case) {
+        let pending = (text_hash(&case.full_text), source_key(&case));
+        batch.insert(
+            &self.pending,
+            id.to_be_bytes(),
+            bincode::encode_to_vec(&pending, standard()).unwrap(),
+        );
+        batch.insert(&db.to_index, id.to_be_bytes(), []);
+        db.insert(batch, id, case);
+    }
+
+    /// Maps the staged cases in id order, removing those already stored under
+    /// another id. Must not run beside an import. Returns the number removed.
+    pub fn resolve(&self, db: &Store) -> usize {
+        let redirects = self
+            .keyspace
+            .open_partition("redirects", PartitionCreateOptions::default())
+            .unwrap();
+        let mut batch = self.keyspace.batch();
+        // Mappings in the batch, which reads do not see before its commit.
+        let mut keys = HashMap::new();
+        let mut hashes = HashMap::new();
+        let (mut staged, mut copies) = (0, 0);
+        for i in self.pending.iter() {
+            let (k, v) = i.unwrap();
+            let id = u32::from_be_bytes(k[..].try_into().unwrap());
+            let ((hash, key), _): ((Option<[u8; 16]>, String), _) =
+                bincode::decode_from_slice(&v, standard()).unwrap();
+            let by_key = keys
+                .get(&key)
+                .copied()
+                .or_else(|| get_id(&self.doc_ids, &key));
+            let by_hash = || {
+                let hash = hash?;
+                hashes
+                    .get(&hash)
+                    .copied()
+                    .or_else(|| get_id(&self.text_hashes, hash))
+            };
+            match by_key.or_else(by_hash) {
+                Some(canonical) if canonical != id => {
+                    db.remove(&mut batch, id);
+                    batch.insert(&redirects, k.clone(), canonical.to_be_bytes());
+                    if by_key.is_none() {
+                        batch.insert(&self.doc_ids, &key, canonical.to_be_bytes());
+                        keys.insert(key, canonical);
+                    }
+                    copies += 1;
+                }
+                _ => {
+                    batch.insert(&self.doc_ids, &key, id.to_be_bytes());
+                    keys.insert(key, id);
+                    if let Some(hash) = hash {
+                        batch.insert(&self.text_hashes, hash, id.to_be_bytes());
+                        hashes.insert(hash, id);
+                    }
+                }
+            }
+            batch.remove(&self.pending, k);
+            staged += 1;
+            if staged % RESOLVE_BATCH == 0 {
+                std::mem::replace(&mut batch, self.keyspace.batch())
+                    .commit()
+                    .unwrap();
+                keys.clear();
+                hashes.clear();
+            }
+        }
+        batch.commit().unwrap();
+        if staged > 0 {
+            info!("mapped {staged} new cases, {copies} copies removed");
+        }
+        copies
+    }
+}
+
+fn get_id(partition: &PartitionHandle, key: impl AsRef<[u8]>) -> Option<u32> {
+    let v = partition.get(key).unwrap()?;
+    Some(u32::from_be_bytes(v[..].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use fjall::Config;
+
+    use super::*;
+    use crate::FullText;
+
+    fn open(name: &str) -> (Keyspace, Store, Ids) {
+        let path = std::env::temp_dir().join(format!("cases-ids-{}-{name}", std::process::id()));
+        let keyspace = Config::new(path).temporary(true).open().unwrap();
+        let db = Store::open(&keyspace);
+        let ids = Ids::open(&keyspace);
+        ids.bootstrap(&db);
+        (keyspace, db, ids)
+    }
+
+    fn case(doc_id: &str, text: &str) -> Case {
+        Case {
+            doc_id: doc_id.to_owned(),
+            case_id: String::new(),
+            case_name: String::new(),
+            court: String::new(),
+            region: String::new(),
+            case_type: String::new(),
+            case_type_code: String::new(),
+            source: String::new(),
+            procedure: String::new(),
+            judgment_date: None,
+            public_date: None,
+            parties: vec![],
+            cause: String::new(),
+            legal_basis: vec![],
+            full_text: FullText::from_plain(text),
+        }
+    }
+
+    fn redirect(keyspace: &Keyspace, id: u32) -> Option<u32> {
+        let redirects = keyspace
+            .open_partition("redirects", PartitionCreateOptions::default())
+            .unwrap();
+        get_id(&redirects, id.to_be_bytes())
+    }
+
+    #[test]
+    fn reserve_unchanged_entry() {
+        let (_keyspace, _db, ids) = open("unchanged");
+        assert_eq!(ids.reserve("a.zip\0a.csv", 7, 10), 1);
+        assert_eq!(ids.reserve("a.zip\0b.csv", 8, 5), 11);
+        // As when resuming an interrupted run.
+        assert_eq!(ids.reserve("a.zip\0a.csv", 7, 10), 1);
+        assert_eq!(ids.reserve("a.zip\0b.csv", 8, 5), 11);
+        assert_eq!(ids.reserve("a.zip\0c.csv", 9, 1), 16);
+    }
+
+    #[test]
+    fn reserve_changed_entry() {
+        let (_keyspace, _db, ids) = open("changed");
+        assert_eq!(ids.reserve("a.csv", 7, 10), 1);
+        assert_eq!(ids.reserve("a.csv", 8, 10), 11);
+        assert_eq!(ids.reserve("a.csv", 8, 12), 21);
+        assert_eq!(ids.reserve("a.csv", 8, 12), 21);
+    }
+
+    #[test]
+    fn resolve_keeps_lowest_id() {
+        let (keyspace, db, ids) = open("resolve");
+        let mut batch = keyspace.batch();
+        // Staged out of order, as by parallel imports.
+        ids.stage(&mut batch, &db, 3, case("a", "另一份 判决书"));
+        ids.stage(&mut batch, &db, 2, case("b", "本院认为 驳回上诉"));
+        ids.stage(&mut batch, &db, 1, case("a", "本院认为 驳回上诉"));
+        ids.stage(&mut batch, &db, 4, case("c", "第三份 判决书"));
+        batch.commit().unwrap();
+
+        assert_eq!(ids.resolve(&db), 2);
+        assert!(db.contains(1));
+        assert!(db.contains(4));
+        // Same text as 1.
+        assert!(!db.contains(2));
+        assert_eq!(redirect(&keyspace, 2), Some(1));
+        assert_eq!(ids.get("b"), Some(1));
+        // Same doc_id as 1.
+        assert!(!db.contains(3));
+        assert_eq!(redirect(&keyspace, 3), Some(1));
+        assert_eq!(ids.get("a"), Some(1));
+        assert_eq!(ids.get("c"), Some(4));
+        assert_eq!(redirect(&keyspace, 4), None);
+
+        // Against the mappings committed by an earlier run.
+        let mut batch = keyspace.batch();
+        ids.stage(&mut batch, &db, 5, case("d", "第三份判决书"));
+        batch.commit().unwrap();
+        assert_eq!(ids.resolve(&db), 1);
+        assert!(!db.contains(5));
+        assert_eq!(redirect(&keyspace, 5), Some(4));
+        assert_eq!(ids.get("d"), Some(4));
+    }
+
+    #[test]
+    fn bootstrap_existing_cases() {
+        let (keyspace, db, _) = open("bootstrap");
+        let mut batch = keyspace.batch();
+        db.insert(&mut batch, 1, case("a", "本院认为 驳回上诉"));
+        db.insert(&mut batch, 2, case("b", "本院认为 驳回上诉"));
+        db.insert(&mut batch, 3, case("a", "另一份 判决书"));
+        batch.commit().unwrap();
+        // As converted by a version without the mappings.
+        let ids = Ids::open(&keyspace);
+        ids.doc_ids.remove(NEXT_ID_KEY).unwrap();
+        assert!(ids.needs_bootstrap());
+
+        ids.bootstrap(&db);
+        assert!(!ids.needs_bootstrap());
+        assert_eq!(ids.get("a"), Some(1));
+        assert_eq!(ids.get("b"), Some(1));
+        assert_eq!(redirect(&keyspace, 2), Some(1));
+        assert_eq!(redirect(&keyspace, 3), Some(1));
+        assert_eq!(ids.reserve("a.csv", 7, 10), 4);
+    }
+}
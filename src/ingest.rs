@@ -0,0 +1,175 @@
+//! Live ingestion: with `ingest_path` set, the server watches that directory
+//! for source files in the formats `convert` reads, stores their new cases,
+//! indexes them with its writer and commits, so they show up in searches
+//! without a restart. Ingested files are moved to its `done` subdirectory,
+//! and files that cannot be read to its `failed` subdirectory.
+//!
+//! A file is taken once its size and modification time have not changed for
+//! a scan, so one still being copied waits. Copying to another name, such as
+//! `cases.jsonl.part`, and renaming it when complete is safer still.
+//!
+//! Files can also be uploaded to `POST /admin/ingest?format=jsonl`, which
+//! drops them into the directory.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+use crate::{Admin, AppState, CONFIG, Ids, Store, admin::authorize, sources};
+
+/// Time between two scans of the drop directory.
+const SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Extensions of the files the upload endpoint accepts.
+const FORMATS: [&str; 5] = ["jsonl", "csv", "csv.gz", "parquet", "zip"];
+
+/// Ingests the files dropped into `dir` on a thread of its own, first indexing
+/// the cases stored since the last `index` run. The ids of the stored cases
+/// must be mapped already, see [`Ids::bootstrap`].
+pub fn watch_ingest(admin: Arc<Admin>, db: Store, ids: Ids, dir: &str) {
+    let dir = PathBuf::from(dir);
+    let done = dir.join("done");
+    let failed = dir.join("failed");
+    fs::create_dir_all(&done).unwrap();
+    fs::create_dir_all(&failed).unwrap();
+    std::thread::spawn(move || {
+        admin.index_new(&db);
+        info!("watching {} for new cases", dir.display());
+        let mut seen = HashMap::new();
+        loop {
+            for path in dropped(&dir, &mut seen) {
+                let time = std::time::Instant::now();
+                let name = path.file_name().unwrap();
+                let to = match admin.ingest(&db, &ids, &path) {
+                    Ok(added) => {
+                        info!(
+                            "ingested {}, {added} new cases, {}",
+                            name.to_string_lossy(),
+                            time.elapsed().as_secs()
+                        );
+                        done.join(name)
+                    }
+                    Err(e) => {
+                        warn!("cannot ingest {}: {e}", name.to_string_lossy());
+                        failed.join(name)
+                    }
+                };
+                // Left in place otherwise, its stored cases are skipped at the
+                // next scan.
+                if let Err(e) = fs::rename(&path, &to) {
+                    warn!("cannot move {} to {}: {e}", path.display(), to.display());
+                }
+            }
+            std::thread::sleep(SCAN_INTERVAL);
+        }
+    });
+}
+
+/// The source files directly in `dir` whose size and modification time are
+/// the same as at the previous scan, recorded in `seen`, by name.
+fn dropped(dir: &Path, seen: &mut HashMap<PathBuf, (u64, SystemTime)>) -> Vec<PathBuf> {
+    let mut current = HashMap::new();
+    let mut paths = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("cannot read {}: {e}", dir.display());
+            return Vec::new();
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_file() || sources(&path).is_empty() {
+            continue;
+        }
+        // Gone meanwhile, or unreadable.
+        let Ok((len, Ok(modified))) = path.metadata().map(|m| (m.len(), m.modified())) else {
+            continue;
+        };
+        let stamp = (len, modified);
+        if seen.get(&path) == Some(&stamp) {
+            paths.push(path.clone());
+        }
+        current.insert(path, stamp);
+    }
+    *seen = current;
+    paths.sort();
+    paths
+}
+
+#[derive(Deserialize)]
+pub struct QueryIngest {
+    format: Option<String>,
+}
+
+/// `POST /admin/ingest`: writes the body to the drop directory as a file of
+/// `format`, `jsonl` by default. Responds with its name once it is complete;
+/// the cases are ingested within two scans.
+pub async fn admin_ingest(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<QueryIngest>,
+    body: Body,
+) -> Response {
+    if let Err(status) = authorize(&state, &headers) {
+        return status.into_response();
+    }
+    let Some(dir) = &CONFIG.ingest_path else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    let format = params.format.as_deref().unwrap_or("jsonl");
+    if !FORMATS.contains(&format) {
+        return (StatusCode::BAD_REQUEST, "Unsupported format").into_response();
+    }
+
+    // Written under a name the scan skips, then renamed.
+    let name = format!("upload-{}.{format}", jiff::Timestamp::now().as_nanosecond());
+    let partial = Path::new(dir).join(format!(".{name}.part"));
+    // Whether the body was complete, or the error writing it.
+    let received = async {
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let Ok(chunk) = chunk else {
+                return Ok(false);
+            };
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&partial, Path::new(dir).join(&name)).await?;
+        io::Result::Ok(true)
+    }
+    .await;
+    let response = match received {
+        Ok(true) => {
+            info!("received {name}");
+            return (StatusCode::ACCEPTED, name).into_response();
+        }
+        Ok(false) => {
+            warn!("upload {name} interrupted");
+            (StatusCode::BAD_REQUEST, "Upload interrupted")
+        }
+        Err(e) => {
+            warn!("cannot write upload {name}: {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Cannot write upload")
+        }
+    };
+    // Possibly not created.
+    let _ = tokio::fs::remove_file(&partial).await;
+    response.into_response()
+}
@@ -1,24 +1,46 @@
-use bincode::{Decode, Encode};
+pub use admin::{Admin, admin_delete_case, admin_update_case};
+pub use api::{api_case, api_search};
+use bincode::{Decode, Encode, config::standard, error::DecodeError};
 pub use config::CONFIG;
-pub use controller::{case, help, search, style};
+pub use controller::{case, case_no, doc, help, search, style};
+pub use export::{CaseColumns, arrow_schema};
 use fjall::{KvSeparationOptions, PartitionCreateOptions, PartitionHandle};
-use scraper::Html;
+pub use ids::Ids;
+pub use ingest::{admin_ingest, watch_ingest};
+use jiff::civil::Date;
 use serde::{Deserialize, Serialize};
+pub use source::{Columns, Entry, Rejects, Source, sources};
 use std::sync::Arc;
+pub use store::Store;
 use tantivy::Searcher;
-pub use tantivy::Tan;
+pub use tantivy::{CaseFields, FACETS, Tan};
+pub use text::{FullText, Kind, Paragraph};
+use xxhash_rust::xxh3::xxh3_128;
 
 #[cfg(feature = "vsearch")]
 use qdrant_client::Qdrant;
 
+mod admin;
+mod api;
 mod config;
 mod controller;
+mod export;
+mod ids;
+mod ingest;
+mod source;
+mod store;
 mod tantivy;
+mod text;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: PartitionHandle,
+    pub db: Store,
+    /// Legacy id → canonical id, for cases stored more than once.
+    pub redirects: PartitionHandle,
     pub searcher: Arc<Searcher>,
+    /// Index writer of the admin endpoints and the live ingestion, when
+    /// `admin_token` or `ingest_path` is set.
+    pub admin: Option<Arc<Admin>>,
     #[cfg(feature = "vsearch")]
     pub qclient: Qdrant,
 }
@@ -33,8 +55,9 @@ pub fn kv_sep_partition_option() -> PartitionCreateOptions {
         )
 }
 
-#[derive(Debug, Encode, Decode, Serialize, Deserialize)]
-pub struct Case {
+/// A case as read from the sources, every field a string.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RawCase {
     #[serde(rename = "原始链接")]
     pub doc_id: String,
     #[serde(rename = "案号")]
@@ -43,14 +66,14 @@ pub struct Case {
     pub case_name: String,
     #[serde(rename = "法院")]
     pub court: String,
-    // #[serde(rename = "所属地区")]
-    // pub region: String,
+    #[serde(rename = "所属地区")]
+    pub region: String,
     #[serde(rename = "案件类型")]
     pub case_type: String,
-    // #[serde(rename = "案件类型编码")]
-    // case_type_code: String,
-    // #[serde(rename = "来源")]
-    // source: String,
+    #[serde(rename = "案件类型编码")]
+    pub case_type_code: String,
+    #[serde(rename = "来源")]
+    pub source: String,
     #[serde(rename = "审理程序")]
     pub procedure: String,
     #[serde(rename = "裁判日期")]
@@ -67,7 +90,519 @@ pub struct Case {
     pub full_text: String,
 }
 
-pub fn remove_html_tags(html: &str) -> String {
-    let document = Html::parse_document(html);
-    document.root_element().text().collect::<Vec<_>>().join(" ")
+/// A stored case, with parsed dates and list fields split. `region`,
+/// `case_type_code` and `source` are empty for cases converted before they
+/// were kept.
+#[derive(Debug, Encode, Decode, Serialize, Deserialize)]
+pub struct Case {
+    pub doc_id: String,
+    pub case_id: String,
+    pub case_name: String,
+    pub court: String,
+    pub region: String,
+    pub case_type: String,
+    pub case_type_code: String,
+    pub source: String,
+    pub procedure: String,
+    #[bincode(with_serde)]
+    pub judgment_date: Option<Date>,
+    #[bincode(with_serde)]
+    pub public_date: Option<Date>,
+    pub parties: Vec<String>,
+    pub cause: String,
+    pub legal_basis: Vec<String>,
+    pub full_text: FullText,
+}
+
+/// Untagged layout of the databases converted before versioning.
+#[derive(Decode)]
+#[cfg_attr(test, derive(Encode))]
+struct CaseV1 {
+    doc_id: String,
+    case_id: String,
+    case_name: String,
+    court: String,
+    case_type: String,
+    procedure: String,
+    judgment_date: String,
+    public_date: String,
+    parties: String,
+    cause: String,
+    legal_basis: String,
+    full_text: String,
+}
+
+/// Version 2 layout, without `region`, `case_type_code` and `source`.
+#[derive(Decode)]
+#[cfg_attr(test, derive(Encode))]
+struct CaseV2 {
+    doc_id: String,
+    case_id: String,
+    case_name: String,
+    court: String,
+    case_type: String,
+    procedure: String,
+    #[bincode(with_serde)]
+    judgment_date: Option<Date>,
+    #[bincode(with_serde)]
+    public_date: Option<Date>,
+    parties: Vec<String>,
+    cause: String,
+    legal_basis: Vec<String>,
+    full_text: String,
+}
+
+/// Version 3 and 4 layout, the full text as HTML or whitespace separated
+/// plain text.
+#[derive(Decode)]
+#[cfg_attr(test, derive(Encode))]
+struct CaseV3 {
+    doc_id: String,
+    case_id: String,
+    case_name: String,
+    court: String,
+    region: String,
+    case_type: String,
+    case_type_code: String,
+    source: String,
+    procedure: String,
+    #[bincode(with_serde)]
+    judgment_date: Option<Date>,
+    #[bincode(with_serde)]
+    public_date: Option<Date>,
+    parties: Vec<String>,
+    cause: String,
+    legal_basis: Vec<String>,
+    full_text: String,
+}
+
+/// First byte of a tagged value. Untagged values start with the varint length
+/// of `doc_id`, and bincode never writes a varint starting with 255.
+const RECORD_MARKER: u8 = 0xff;
+
+/// Version of the stored [`Case`] layout, after [`RECORD_MARKER`]. Since
+/// version 4 the full text is stored apart, see [`Store`], and since version 5
+/// as [`FullText`] paragraphs.
+pub const RECORD_VERSION: u8 = 5;
+
+impl Case {
+    /// The value stored in the `cases` partition: marker, version, then the
+    /// case, whose full text [`Store::insert`] has moved out.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut v = vec![RECORD_MARKER, RECORD_VERSION];
+        bincode::encode_into_std_write(self, &mut v, standard()).unwrap();
+        v
+    }
+
+    /// Decodes a stored value of the current version or of an earlier one,
+    /// the fields it lacks left empty.
+    pub fn decode(v: &[u8]) -> Result<Case, DecodeError> {
+        match v {
+            [RECORD_MARKER, RECORD_VERSION, rest @ ..] => {
+                Ok(bincode::decode_from_slice(rest, standard())?.0)
+            }
+            [RECORD_MARKER, 3 | 4, rest @ ..] => {
+                let (old, _): (CaseV3, _) = bincode::decode_from_slice(rest, standard())?;
+                Ok(old.into())
+            }
+            [RECORD_MARKER, 2, rest @ ..] => {
+                let (old, _): (CaseV2, _) = bincode::decode_from_slice(rest, standard())?;
+                Ok(old.into())
+            }
+            [RECORD_MARKER, version, ..] => Err(DecodeError::Other(if *version > RECORD_VERSION {
+                "case written by a newer version"
+            } else {
+                "unknown case version"
+            })),
+            _ => {
+                let (old, _): (CaseV1, _) = bincode::decode_from_slice(v, standard())?;
+                Ok(old.into())
+            }
+        }
+    }
+
+    /// Whether `v` is stored in the current layout, with the full text apart.
+    pub fn is_current(v: &[u8]) -> bool {
+        v.starts_with(&[RECORD_MARKER, RECORD_VERSION])
+    }
+
+    /// Layout version of a stored value, 1 for untagged ones.
+    pub fn version(v: &[u8]) -> u8 {
+        match v {
+            [RECORD_MARKER, version, ..] => *version,
+            _ => 1,
+        }
+    }
+}
+
+impl From<RawCase> for Case {
+    /// Parses the fields, and keeps only the docId of a wenshu link such as
+    /// `https://wenshu.court.gov.cn/website/wenshu/181107ANFZ0BXSK4/index.html?docId=964fc681687d4e47a0a9ace500096dde`.
+    fn from(raw: RawCase) -> Self {
+        let doc_id = match raw.doc_id.rsplit_once('=') {
+            Some((_, doc_id)) => doc_id.to_owned(),
+            None => raw.doc_id,
+        };
+        Case {
+            doc_id,
+            case_id: raw.case_id,
+            case_name: raw.case_name,
+            court: raw.court,
+            region: raw.region,
+            case_type: raw.case_type,
+            case_type_code: raw.case_type_code,
+            source: raw.source,
+            procedure: raw.procedure,
+            judgment_date: parse_date(&raw.judgment_date),
+            public_date: parse_date(&raw.public_date),
+            parties: split_list(&raw.parties),
+            cause: raw.cause,
+            legal_basis: split_list(&raw.legal_basis),
+            full_text: FullText::from_raw(&raw.full_text),
+        }
+    }
+}
+
+impl From<CaseV1> for Case {
+    fn from(old: CaseV1) -> Self {
+        RawCase {
+            doc_id: old.doc_id,
+            case_id: old.case_id,
+            case_name: old.case_name,
+            court: old.court,
+            region: String::new(),
+            case_type: old.case_type,
+            case_type_code: String::new(),
+            source: String::new(),
+            procedure: old.procedure,
+            judgment_date: old.judgment_date,
+            public_date: old.public_date,
+            parties: old.parties,
+            cause: old.cause,
+            legal_basis: old.legal_basis,
+            full_text: old.full_text,
+        }
+        .into()
+    }
+}
+
+impl From<CaseV2> for Case {
+    fn from(old: CaseV2) -> Self {
+        Case {
+            doc_id: old.doc_id,
+            case_id: old.case_id,
+            case_name: old.case_name,
+            court: old.court,
+            region: String::new(),
+            case_type: old.case_type,
+            case_type_code: String::new(),
+            source: String::new(),
+            procedure: old.procedure,
+            judgment_date: old.judgment_date,
+            public_date: old.public_date,
+            parties: old.parties,
+            cause: old.cause,
+            legal_basis: old.legal_basis,
+            full_text: FullText::from_raw(&old.full_text),
+        }
+    }
+}
+
+impl From<CaseV3> for Case {
+    fn from(old: CaseV3) -> Self {
+        Case {
+            doc_id: old.doc_id,
+            case_id: old.case_id,
+            case_name: old.case_name,
+            court: old.court,
+            region: old.region,
+            case_type: old.case_type,
+            case_type_code: old.case_type_code,
+            source: old.source,
+            procedure: old.procedure,
+            judgment_date: old.judgment_date,
+            public_date: old.public_date,
+            parties: old.parties,
+            cause: old.cause,
+            legal_basis: old.legal_basis,
+            full_text: FullText::from_raw(&old.full_text),
+        }
+    }
+}
+
+/// A date as written in the sources, `2021-03-15`, `2021/3/15` or
+/// `2021年3月15日`, possibly followed by a time.
+pub fn parse_date(s: &str) -> Option<Date> {
+    let s = s.trim().split([' ', 'T']).next()?;
+    let mut parts = s
+        .split(['-', '/', '.', '年', '月', '日'])
+        .filter(|p| !p.is_empty());
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let day = parts.next()?.parse().ok()?;
+    Date::new(year, month, day).ok()
+}
+
+/// `date` as `yyyy-mm-dd`, or empty.
+pub fn format_date(date: Option<Date>) -> String {
+    date.map(|d| d.to_string()).unwrap_or_default()
+}
+
+/// The items of a comma separated list such as `parties`, trimmed.
+pub fn split_list(s: &str) -> Vec<String> {
+    s.split([',', '，'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// `2021-03-15` as `20210315`, for sorting and range queries on dates.
+pub fn date_number(date: Date) -> u64 {
+    date.year() as u64 * 10000 + date.month() as u64 * 100 + date.day() as u64
+}
+
+/// Key of the source document of a case in the `doc_ids` partition: the wenshu
+/// docId, or the case number, court and judgment date when there is none.
+pub fn source_key(case: &Case) -> String {
+    if case.doc_id.is_empty() {
+        format!(
+            "{}|{}|{}",
+            case.case_id,
+            case.court,
+            format_date(case.judgment_date)
+        )
+    } else {
+        case.doc_id.clone()
+    }
+}
+
+/// Hash of the full text ignoring whitespace, to find exact duplicates. `None`
+/// for an empty text.
+pub fn text_hash(full_text: &FullText) -> Option<[u8; 16]> {
+    let text: String = full_text
+        .to_text()
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    (!text.is_empty()).then(|| xxh3_128(text.as_bytes()).to_be_bytes())
+}
+
+/// Canonical form of a case number (案号) for exact lookup: brackets unified to
+/// ASCII parentheses and whitespace removed.
+pub fn normalize_case_no(case_no: &str) -> String {
+    case_no
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            '（' | '〔' | '[' | '【' => '(',
+            '）' | '〕' | ']' | '】' => ')',
+            c => c,
+        })
+        .collect()
+}
+
+/// The normalized case number if `s` looks like one, such as `(2021)京01民终1234号`.
+pub fn parse_case_no(s: &str) -> Option<String> {
+    let case_no = normalize_case_no(s);
+    let rest = case_no.strip_prefix('(')?;
+    let (year, rest) = rest.split_once(')')?;
+    let body = rest.strip_suffix('号')?;
+    (year.len() == 4
+        && year.bytes().all(|b| b.is_ascii_digit())
+        && body.chars().any(|c| !c.is_ascii_digit())
+        && body.ends_with(|c: char| c.is_ascii_digit()))
+    .then_some(case_no)
+}
+
+/// Court level derived from the court name, used as a facet.
+pub fn court_level(court: &str) -> &'static str {
+    if court.contains("最高人民法院") {
+        "最高人民法院"
+    } else if court.contains("高级人民法院") {
+        "高级人民法院"
+    } else if court.contains("中级人民法院") {
+        "中级人民法院"
+    } else if court.ends_with("人民法院") || court.ends_with("人民法庭") {
+        "基层人民法院"
+    } else {
+        "专门法院"
+    }
+}
+
+pub(crate) fn escape_html(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(year: i16, month: i8, day: i8) -> Option<Date> {
+        Some(Date::new(year, month, day).unwrap())
+    }
+
+    fn tagged(version: u8, value: &impl Encode) -> Vec<u8> {
+        let mut v = vec![RECORD_MARKER, version];
+        bincode::encode_into_std_write(value, &mut v, standard()).unwrap();
+        v
+    }
+
+    fn v1() -> CaseV1 {
+        CaseV1 {
+            doc_id: "964fc681687d4e47a0a9ace500096dde".to_owned(),
+            case_id: "（2021）京01民终1234号".to_owned(),
+            case_name: "张三与李四借款合同纠纷".to_owned(),
+            court: "北京市第一中级人民法院".to_owned(),
+            case_type: "民事案件".to_owned(),
+            procedure: "民事二审".to_owned(),
+            judgment_date: "2021-03-15".to_owned(),
+            public_date: "2021/4/1".to_owned(),
+            parties: "张三,李四，王五".to_owned(),
+            cause: "借款合同纠纷".to_owned(),
+            legal_basis: "".to_owned(),
+            full_text: "<p>本院认为</p><p>驳回上诉</p>".to_owned(),
+        }
+    }
+
+    fn v3() -> CaseV3 {
+        CaseV3 {
+            doc_id: "964fc681687d4e47a0a9ace500096dde".to_owned(),
+            case_id: "（2021）京01民终1234号".to_owned(),
+            case_name: "张三与李四借款合同纠纷".to_owned(),
+            court: "北京市第一中级人民法院".to_owned(),
+            region: "北京市".to_owned(),
+            case_type: "民事案件".to_owned(),
+            case_type_code: "2".to_owned(),
+            source: "中国裁判文书网".to_owned(),
+            procedure: "民事二审".to_owned(),
+            judgment_date: date(2021, 3, 15),
+            public_date: None,
+            parties: vec!["张三".to_owned(), "李四".to_owned()],
+            cause: "借款合同纠纷".to_owned(),
+            legal_basis: vec!["《中华人民共和国民法典》第六百七十五条".to_owned()],
+            full_text: "本院认为 驳回上诉".to_owned(),
+        }
+    }
+
+    fn body(case: &Case) -> Vec<&str> {
+        case.full_text.0.iter().map(|p| p.text.as_str()).collect()
+    }
+
+    #[test]
+    fn decodes_untagged_v1() {
+        let v = bincode::encode_to_vec(v1(), standard()).unwrap();
+        assert_eq!(Case::version(&v), 1);
+        assert!(!Case::is_current(&v));
+        let case = Case::decode(&v).unwrap();
+        assert_eq!(case.case_id, "（2021）京01民终1234号");
+        assert_eq!(case.judgment_date, date(2021, 3, 15));
+        assert_eq!(case.public_date, date(2021, 4, 1));
+        assert_eq!(case.parties, ["张三", "李四", "王五"]);
+        assert!(case.legal_basis.is_empty());
+        assert!(case.region.is_empty());
+        assert_eq!(body(&case), ["本院认为", "驳回上诉"]);
+    }
+
+    #[test]
+    fn untagged_values_never_start_with_the_marker() {
+        for len in [0, 1, 250, 251, 252, 300, 65_535, 65_536, 100_000] {
+            let case = CaseV1 {
+                doc_id: "x".repeat(len),
+                ..v1()
+            };
+            let v = bincode::encode_to_vec(case, standard()).unwrap();
+            assert_ne!(v[0], RECORD_MARKER, "doc_id of {len} bytes");
+            assert_eq!(Case::decode(&v).unwrap().doc_id.len(), len);
+        }
+    }
+
+    #[test]
+    fn decodes_v2() {
+        let old = v3();
+        let v = tagged(
+            2,
+            &CaseV2 {
+                doc_id: old.doc_id,
+                case_id: old.case_id,
+                case_name: old.case_name,
+                court: old.court,
+                case_type: old.case_type,
+                procedure: old.procedure,
+                judgment_date: old.judgment_date,
+                public_date: old.public_date,
+                parties: old.parties,
+                cause: old.cause,
+                legal_basis: old.legal_basis,
+                full_text: old.full_text,
+            },
+        );
+        let case = Case::decode(&v).unwrap();
+        assert_eq!(case.case_name, "张三与李四借款合同纠纷");
+        assert_eq!(case.judgment_date, date(2021, 3, 15));
+        assert_eq!(case.public_date, None);
+        assert_eq!(case.parties, ["张三", "李四"]);
+        assert!(case.region.is_empty() && case.source.is_empty());
+        assert_eq!(body(&case), ["本院认为", "驳回上诉"]);
+    }
+
+    #[test]
+    fn decodes_v3_and_v4() {
+        for version in [3, 4] {
+            let v = tagged(version, &v3());
+            assert_eq!(Case::version(&v), version);
+            assert!(!Case::is_current(&v));
+            let case = Case::decode(&v).unwrap();
+            assert_eq!(case.region, "北京市");
+            assert_eq!(case.case_type_code, "2");
+            assert_eq!(case.source, "中国裁判文书网");
+            assert_eq!(case.legal_basis.len(), 1);
+            assert_eq!(body(&case), ["本院认为", "驳回上诉"]);
+        }
+    }
+
+    #[test]
+    fn current_version_round_trips() {
+        let mut case = Case::decode(&tagged(3, &v3())).unwrap();
+        case.public_date = date(2021, 4, 1);
+        let v = case.encode();
+        assert_eq!(Case::version(&v), RECORD_VERSION);
+        assert!(Case::is_current(&v));
+        let decoded = Case::decode(&v).unwrap();
+        assert_eq!(decoded.public_date, date(2021, 4, 1));
+        assert_eq!(decoded.full_text, case.full_text);
+        assert_eq!(decoded.legal_basis, case.legal_basis);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let case = Case::decode(&tagged(3, &v3())).unwrap();
+        let mut v = case.encode();
+        v[1] = RECORD_VERSION + 1;
+        assert!(Case::decode(&v).is_err());
+        v[1] = 1;
+        assert!(Case::decode(&v).is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("2021-03-15"), date(2021, 3, 15));
+        assert_eq!(parse_date(" 2021/3/5 "), date(2021, 3, 5));
+        assert_eq!(parse_date("2021.03.15"), date(2021, 3, 15));
+        assert_eq!(parse_date("2021年3月15日"), date(2021, 3, 15));
+        assert_eq!(parse_date("2021-03-15 00:00:00"), date(2021, 3, 15));
+        assert_eq!(parse_date("2021-03-15T08:30:00"), date(2021, 3, 15));
+        assert_eq!(parse_date(""), None);
+        assert_eq!(parse_date("2021-02-30"), None);
+        assert_eq!(parse_date("2021-03"), None);
+        assert_eq!(parse_date("不详"), None);
+    }
 }
@@ -0,0 +1,636 @@
+//! Input files for `convert`: the zipped CSVs of the torrent, plain or gzip'd
+//! CSVs, JSON Lines and Parquet, with the columns mapped to [`RawCase`] fields
+//! through the `[columns]` table of the config.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Read},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use arrow::{
+    array::{Array, AsArray},
+    compute::cast,
+    datatypes::DataType,
+};
+use flate2::read::MultiGzDecoder;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use serde_json::{Map, Value};
+use tracing::warn;
+
+use crate::{CONFIG, RawCase};
+
+/// `RawCase` fields with the column names looked up for them by default, the
+/// headers of the torrent CSVs and the names used by the exports.
+const FIELDS: [(&str, &[&str]); 15] = [
+    ("doc_id", &["原始链接", "doc_id", "url"]),
+    ("case_id", &["案号", "case_id"]),
+    ("case_name", &["案件名称", "case_name"]),
+    ("court", &["法院", "court"]),
+    ("region", &["所属地区", "region"]),
+    ("case_type", &["案件类型", "case_type"]),
+    ("case_type_code", &["案件类型编码", "case_type_code"]),
+    ("source", &["来源", "source"]),
+    ("procedure", &["审理程序", "procedure"]),
+    ("judgment_date", &["裁判日期", "judgment_date"]),
+    ("public_date", &["公开日期", "public_date"]),
+    ("parties", &["当事人", "parties"]),
+    ("cause", &["案由", "cause"]),
+    ("legal_basis", &["法律依据", "legal_basis"]),
+    ("full_text", &["全文", "full_text"]),
+];
+
+/// Column names to read each `RawCase` field from, in [`FIELDS`] order.
+pub struct Columns {
+    names: Vec<Vec<String>>,
+}
+
+impl Default for Columns {
+    /// The default names, with the fields set in `[columns]` replaced.
+    fn default() -> Self {
+        let names = FIELDS
+            .iter()
+            .map(
+                |(field, defaults)| match CONFIG.columns.as_ref().and_then(|c| c.get(*field)) {
+                    Some(name) => vec![name.clone()],
+                    None => defaults.iter().map(|name| name.to_string()).collect(),
+                },
+            )
+            .collect();
+        Columns { names }
+    }
+}
+
+impl Columns {
+    /// Position in `headers` of the column of each field.
+    fn indices<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a str> + Clone,
+    ) -> Vec<Option<usize>> {
+        self.names
+            .iter()
+            .map(|names| {
+                names.iter().find_map(|name| {
+                    headers
+                        .clone()
+                        .into_iter()
+                        .position(|header| header.trim_start_matches('\u{feff}') == name)
+                })
+            })
+            .collect()
+    }
+
+    pub(crate) fn json_case(&self, object: &Map<String, Value>) -> RawCase {
+        self.json_update(object, std::iter::repeat_with(String::new))
+    }
+
+    /// Like [`Columns::json_case`], keeping the `current` values, in [`FIELDS`]
+    /// order, of the fields `object` does not set.
+    pub(crate) fn json_update(
+        &self,
+        object: &Map<String, Value>,
+        current: impl IntoIterator<Item = String>,
+    ) -> RawCase {
+        case(self.names.iter().zip(current).map(|(names, current)| {
+            match names.iter().find_map(|name| object.get(name)) {
+                Some(Value::String(s)) => s.clone(),
+                Some(Value::Null) => String::new(),
+                Some(v) => v.to_string(),
+                None => current,
+            }
+        }))
+    }
+
+    /// Whether `object` sets the [`FIELDS`] entry `field`.
+    pub(crate) fn json_sets(&self, object: &Map<String, Value>, field: &str) -> bool {
+        FIELDS
+            .iter()
+            .position(|(name, _)| *name == field)
+            .is_some_and(|i| self.names[i].iter().any(|name| object.contains_key(name)))
+    }
+}
+
+/// Builds a case from its field values in [`FIELDS`] order.
+fn case(mut values: impl Iterator<Item = String>) -> RawCase {
+    let mut next = || values.next().unwrap_or_default();
+    RawCase {
+        doc_id: next(),
+        case_id: next(),
+        case_name: next(),
+        court: next(),
+        region: next(),
+        case_type: next(),
+        case_type_code: next(),
+        source: next(),
+        procedure: next(),
+        judgment_date: next(),
+        public_date: next(),
+        parties: next(),
+        cause: next(),
+        legal_basis: next(),
+        full_text: next(),
+    }
+}
+
+/// A part of a source imported as a unit: a CSV in a zip archive, or the whole
+/// file for the other formats.
+pub struct Entry {
+    pub name: String,
+    /// CRC-32 of the uncompressed entry.
+    pub crc32: u32,
+}
+
+/// A file of raw cases.
+pub trait Source: Send + Sync {
+    fn path(&self) -> &Path;
+
+    /// The entries, or an error if the file cannot be opened as this format.
+    fn entries(&self) -> io::Result<Vec<Entry>>;
+
+    /// Calls `f` with each case of `entry` in order, until it breaks. Rows
+    /// that cannot be read are written to `rejects` and skipped; an error
+    /// means the entry could not be read through, possibly after some cases.
+    fn for_each(
+        &self,
+        entry: &str,
+        columns: &Columns,
+        rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()>;
+
+    /// Number of cases of `entry`, the ids it takes.
+    fn count(&self, entry: &str, columns: &Columns) -> io::Result<u32> {
+        let mut count = 0;
+        self.for_each(entry, columns, &Rejects::ignore(), &mut |_| {
+            count += 1;
+            ControlFlow::Continue(())
+        })?;
+        Ok(count)
+    }
+}
+
+/// Rows skipped because they could not be read, appended to a CSV file of
+/// source, entry, line, error and raw record.
+pub struct Rejects {
+    path: PathBuf,
+    writer: Option<Mutex<csv::Writer<File>>>,
+    count: AtomicUsize,
+}
+
+impl Rejects {
+    /// Appends to the file at `path`, writing the header if it is new.
+    pub fn open(path: impl AsRef<Path>) -> Rejects {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        let is_new = file.metadata().unwrap().len() == 0;
+        let mut writer = csv::Writer::from_writer(file);
+        if is_new {
+            writer
+                .write_record(["source", "entry", "line", "error", "record"])
+                .unwrap();
+            writer.flush().unwrap();
+        }
+        Rejects {
+            path,
+            writer: Some(Mutex::new(writer)),
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Drops the rejected rows, for a pass that only counts the others.
+    pub fn ignore() -> Rejects {
+        Rejects {
+            path: PathBuf::new(),
+            writer: None,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Rows rejected since [`Rejects::open`].
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    fn reject(&self, source: &Path, entry: &str, line: u64, error: &str, record: &str) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        warn!("rejected {} {entry} line {line}: {error}", source.display());
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let mut writer = writer.lock().unwrap();
+        writer
+            .write_record([
+                &source.display().to_string(),
+                entry,
+                &line.to_string(),
+                error,
+                record,
+            ])
+            .unwrap();
+        writer.flush().unwrap();
+    }
+}
+
+/// The only entry of a plain file, checksummed by reading it through.
+fn file_entry(path: &Path) -> io::Result<Vec<Entry>> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(vec![Entry {
+        name: path.file_name().unwrap().to_string_lossy().into_owned(),
+        crc32: hasher.finalize(),
+    }])
+}
+
+/// The sources under `path` by extension, sorted by path so ids are reserved
+/// in the same order on every machine. Directories are walked recursively, so
+/// an unzipped dump can be read as is.
+pub fn sources(path: &Path) -> Vec<Box<dyn Source>> {
+    let mut sources: Vec<Box<dyn Source>> = Vec::new();
+    if path.is_dir() {
+        let mut paths: Vec<_> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        paths.sort();
+        for path in paths {
+            sources.extend(self::sources(&path));
+        }
+        return sources;
+    }
+    let path = path.to_path_buf();
+    let name = path.to_string_lossy().to_lowercase();
+    if name.ends_with(".zip") {
+        sources.push(Box::new(ZipCsv(path)));
+    } else if name.ends_with(".csv") {
+        sources.push(Box::new(Csv(path)));
+    } else if name.ends_with(".csv.gz") {
+        sources.push(Box::new(GzCsv(path)));
+    } else if name.ends_with(".jsonl") || name.ends_with(".ndjson") {
+        sources.push(Box::new(Jsonl(path)));
+    } else if name.ends_with(".parquet") {
+        sources.push(Box::new(Parquet(path)));
+    }
+    sources
+}
+
+/// The fields of `record` written back as a CSV line.
+fn raw_record(record: &csv::ByteRecord) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    writer.write_byte_record(record).unwrap();
+    let raw = writer.into_inner().unwrap();
+    String::from_utf8_lossy(raw.strip_suffix(b"\n").unwrap_or(&raw)).into_owned()
+}
+
+/// Reads the CSV of `entry`, rejecting rows with a wrong column count or
+/// invalid UTF-8. A read error ends the entry, as the rest cannot be trusted,
+/// and is returned.
+fn read_csv(
+    reader: impl Read,
+    source: &Path,
+    entry: &str,
+    columns: &Columns,
+    rejects: &Rejects,
+    f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+) -> io::Result<()> {
+    let mut rdr = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+    let headers = match rdr.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            rejects.reject(source, entry, 1, &e.to_string(), "");
+            return Err(e.into());
+        }
+    };
+    let indices = columns.indices(headers.iter());
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let line = rdr.position().line();
+        match rdr.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                rejects.reject(source, entry, line, &e.to_string(), "");
+                return Err(e.into());
+            }
+        }
+        let line = record.position().map_or(line, |p| p.line());
+        if record.len() != headers.len() {
+            let error = format!("expected {} fields, found {}", headers.len(), record.len());
+            rejects.reject(source, entry, line, &error, &raw_record(&record));
+            continue;
+        }
+        let values: Result<Vec<String>, _> = indices
+            .iter()
+            .map(|i| {
+                let field = i.and_then(|i| record.get(i)).unwrap_or_default();
+                std::str::from_utf8(field).map(str::to_owned)
+            })
+            .collect();
+        match values {
+            Ok(values) => {
+                if f(case(values.into_iter())).is_break() {
+                    break;
+                }
+            }
+            Err(e) => rejects.reject(source, entry, line, &e.to_string(), &raw_record(&record)),
+        }
+    }
+    Ok(())
+}
+
+/// Zip archive of CSVs, as in the torrent.
+pub struct ZipCsv(PathBuf);
+
+impl Source for ZipCsv {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        let file = File::open(&self.0)?;
+        let mut archive = zip::ZipArchive::new(file)?;
+        let mut entries = Vec::new();
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            if file.name().ends_with(".csv") {
+                entries.push(Entry {
+                    name: file.name().to_owned(),
+                    crc32: file.crc32(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn for_each(
+        &self,
+        entry: &str,
+        columns: &Columns,
+        rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        let file = File::open(&self.0)?;
+        let mut archive = zip::ZipArchive::new(file)?;
+        let reader = archive.by_name(entry)?;
+        read_csv(reader, &self.0, entry, columns, rejects, f)
+    }
+}
+
+pub struct Csv(PathBuf);
+
+impl Source for Csv {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        file_entry(&self.0)
+    }
+
+    fn for_each(
+        &self,
+        entry: &str,
+        columns: &Columns,
+        rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        let file = File::open(&self.0)?;
+        read_csv(file, &self.0, entry, columns, rejects, f)
+    }
+}
+
+pub struct GzCsv(PathBuf);
+
+impl Source for GzCsv {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        file_entry(&self.0)
+    }
+
+    fn for_each(
+        &self,
+        entry: &str,
+        columns: &Columns,
+        rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        let file = MultiGzDecoder::new(File::open(&self.0)?);
+        read_csv(file, &self.0, entry, columns, rejects, f)
+    }
+}
+
+/// One JSON object per line, such as the `format=jsonl` export.
+pub struct Jsonl(PathBuf);
+
+impl Source for Jsonl {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        file_entry(&self.0)
+    }
+
+    fn for_each(
+        &self,
+        entry: &str,
+        columns: &Columns,
+        rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        let reader = BufReader::new(File::open(&self.0)?);
+        for (i, line) in reader.lines().enumerate() {
+            let line_no = i as u64 + 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    rejects.reject(&self.0, entry, line_no, &e.to_string(), "");
+                    return Err(e);
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let object: Map<String, Value> = match serde_json::from_str(&line) {
+                Ok(object) => object,
+                Err(e) => {
+                    rejects.reject(&self.0, entry, line_no, &e.to_string(), &line);
+                    continue;
+                }
+            };
+            if f(columns.json_case(&object)).is_break() {
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parquet file, such as those written by `examples/parquet.rs`.
+pub struct Parquet(PathBuf);
+
+impl Source for Parquet {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn entries(&self) -> io::Result<Vec<Entry>> {
+        file_entry(&self.0)
+    }
+
+    fn for_each(
+        &self,
+        _entry: &str,
+        columns: &Columns,
+        _rejects: &Rejects,
+        f: &mut dyn FnMut(RawCase) -> ControlFlow<()>,
+    ) -> io::Result<()> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&self.0)?)
+            .and_then(|builder| builder.build())
+            .map_err(io::Error::other)?;
+        for batch in reader {
+            let batch = batch.map_err(io::Error::other)?;
+            let schema = batch.schema();
+            let indices = columns.indices(schema.fields().iter().map(|f| f.name().as_str()));
+            let arrays: Vec<_> = indices
+                .iter()
+                .map(|i| {
+                    i.map(|i| cast(batch.column(i), &DataType::Utf8))
+                        .transpose()
+                })
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?;
+            for row in 0..batch.num_rows() {
+                let values = arrays.iter().map(|array| match array {
+                    Some(array) if array.is_valid(row) => {
+                        array.as_string::<i32>().value(row).to_owned()
+                    }
+                    _ => String::new(),
+                });
+                if f(case(values)).is_break() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns() -> Columns {
+        let names = FIELDS
+            .iter()
+            .map(|(_, defaults)| defaults.iter().map(|name| name.to_string()).collect())
+            .collect();
+        Columns { names }
+    }
+
+    /// Reads `csv` through [`read_csv`], returning the doc ids passed on, the
+    /// result and the rejects file.
+    fn read(name: &str, csv: impl Read) -> (Vec<String>, io::Result<()>, Rejects) {
+        let path = std::env::temp_dir().join(format!("cases-{}-{name}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let rejects = Rejects::open(&path);
+        let mut doc_ids = vec![];
+        let result = read_csv(
+            csv,
+            Path::new("a.zip"),
+            "a.csv",
+            &columns(),
+            &rejects,
+            &mut |raw| {
+                doc_ids.push(raw.doc_id);
+                ControlFlow::Continue(())
+            },
+        );
+        (doc_ids, result, rejects)
+    }
+
+    fn rejected(rejects: &Rejects) -> Vec<(String, String)> {
+        let mut reader = csv::Reader::from_path(rejects.path()).unwrap();
+        let rows = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap();
+                (record[2].to_owned(), record[4].to_owned())
+            })
+            .collect();
+        fs::remove_file(rejects.path()).unwrap();
+        rows
+    }
+
+    #[test]
+    fn reject_rows() {
+        let mut csv = b"doc_id,case_id,full_text\n".to_vec();
+        csv.extend("a,（2021）京01民终1号,本院认为\n".as_bytes());
+        csv.extend("b,（2021）京01民终2号\n".as_bytes());
+        csv.extend(b"c,\xff\xfe,text\n");
+        csv.extend("\"d\",（2021）京01民终4号,\"驳回\n上诉\"\n".as_bytes());
+        csv.extend("e,（2021）京01民终5号,驳回,上诉\n".as_bytes());
+        let (doc_ids, result, rejects) = read("reject", &csv[..]);
+
+        assert!(result.is_ok());
+        assert_eq!(doc_ids, ["a", "d"]);
+        assert_eq!(rejects.count(), 3);
+        assert_eq!(
+            rejected(&rejects),
+            [
+                ("3".to_owned(), "b,（2021）京01民终2号".to_owned()),
+                ("4".to_owned(), "c,\u{fffd}\u{fffd},text".to_owned()),
+                ("7".to_owned(), "e,（2021）京01民终5号,驳回,上诉".to_owned()),
+            ]
+        );
+    }
+
+    /// Fails after the rows before it, as a corrupt archive does.
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid checksum",
+            ))
+        }
+    }
+
+    #[test]
+    fn read_error() {
+        let csv = "doc_id,case_id,full_text\na,（2021）京01民终1号,本院认为\n";
+        let (doc_ids, result, rejects) = read("broken", csv.as_bytes().chain(Broken));
+
+        assert!(result.is_err());
+        assert_eq!(doc_ids, ["a"]);
+        assert_eq!(rejects.count(), 1);
+        assert_eq!(rejected(&rejects), [("3".to_owned(), String::new())]);
+    }
+}
@@ -0,0 +1,261 @@
+//! The stored cases, split so that listing them does not read the judgments:
+//! metadata in `cases`, the plain-text beginning of the full text in
+//! `previews`, and the full text in `texts`, all keyed by id.
+//!
+//! Databases converted before the split keep the full text inline in `cases`,
+//! which is still read until `migrate` moves it out. The full text is stored
+//! as bincode [`FullText`] paragraphs, or as HTML for cases of version 4.
+//!
+//! Once `migrate config.toml compress` has trained a zstd dictionary on the
+//! stored texts, new texts are compressed with it. Texts are told apart by the
+//! zstd magic number, which neither encoded paragraphs nor UTF-8 text start
+//! with, and each frame names the dictionary it needs, so texts compressed with
+//! a retired dictionary stay readable.
+//!
+//! Cases taken down through the admin endpoints are recorded in `withdrawn`,
+//! so that `convert` does not bring them back.
+//!
+//! New cases are listed in `to_index` until they are indexed. Imports store
+//! their reserved ids in any order, so `index` cannot rely on resuming after
+//! the last id it indexed alone.
+
+use std::{collections::HashMap, io::Read, sync::Arc};
+
+use bincode::config::standard;
+
+use fjall::{Batch, Keyspace, PartitionCreateOptions, PartitionHandle};
+use zstd::{
+    bulk::Compressor,
+    dict::{DecoderDictionary, EncoderDictionary},
+    stream::read::Decoder,
+    zstd_safe,
+};
+
+use crate::{Case, FullText, kv_sep_partition_option};
+
+/// Chars of plain text kept in `previews`, enough for the snippet generator to
+/// find a passage around the query terms near the start of the judgment. For
+/// terms further on, the search results show the start instead.
+const PREVIEW_SOURCE_CHARS: usize = 1000;
+
+/// Start of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression level of the texts; higher levels cost a lot more time in
+/// `convert` for little gain once the dictionary removes the boilerplate.
+const ZSTD_LEVEL: i32 = 9;
+
+/// `dicts` key of the id of the dictionary new texts are compressed with.
+/// Dictionaries themselves are keyed by their big-endian id.
+const ACTIVE_DICT: &[u8] = b"active";
+
+#[derive(Clone)]
+pub struct Store {
+    pub cases: PartitionHandle,
+    pub previews: PartitionHandle,
+    pub texts: PartitionHandle,
+    pub dicts: PartitionHandle,
+    /// Ids of the withdrawn cases, with the time they were withdrawn.
+    pub withdrawn: PartitionHandle,
+    /// Ids of the new cases not indexed yet.
+    pub to_index: PartitionHandle,
+    /// Id and prepared form of the active dictionary, if any.
+    encoder: Option<(u32, Arc<EncoderDictionary<'static>>)>,
+    decoders: Arc<HashMap<u32, DecoderDictionary<'static>>>,
+}
+
+impl Store {
+    pub fn open(keyspace: &Keyspace) -> Store {
+        let dicts = keyspace
+            .open_partition("dicts", PartitionCreateOptions::default())
+            .unwrap();
+        let mut decoders = HashMap::new();
+        let mut encoder = None;
+        let active = dicts.get(ACTIVE_DICT).unwrap();
+        for i in dicts.iter() {
+            let (k, v) = i.unwrap();
+            let Ok(id) = k[..].try_into().map(u32::from_be_bytes) else {
+                continue;
+            };
+            if active.as_deref() == Some(&k[..]) {
+                encoder = Some((id, Arc::new(EncoderDictionary::copy(&v, ZSTD_LEVEL))));
+            }
+            decoders.insert(id, DecoderDictionary::copy(&v));
+        }
+        Store {
+            cases: keyspace
+                .open_partition("cases", kv_sep_partition_option())
+                .unwrap(),
+            previews: keyspace
+                .open_partition("previews", PartitionCreateOptions::default())
+                .unwrap(),
+            texts: keyspace
+                .open_partition("texts", kv_sep_partition_option())
+                .unwrap(),
+            dicts,
+            withdrawn: keyspace
+                .open_partition("withdrawn", PartitionCreateOptions::default())
+                .unwrap(),
+            to_index: keyspace
+                .open_partition("to_index", PartitionCreateOptions::default())
+                .unwrap(),
+            encoder,
+            decoders: Arc::new(decoders),
+        }
+    }
+
+    pub fn contains(&self, id: u32) -> bool {
+        self.cases.contains_key(id.to_be_bytes()).unwrap()
+    }
+
+    /// Case `id` without its full text.
+    pub fn meta(&self, id: u32) -> Option<Case> {
+        let v = self.cases.get(id.to_be_bytes()).unwrap()?;
+        let mut case = Case::decode(&v).unwrap();
+        case.full_text = FullText::default();
+        Some(case)
+    }
+
+    /// Case `id` with its full text.
+    pub fn case(&self, id: u32) -> Option<Case> {
+        let v = self.cases.get(id.to_be_bytes()).unwrap()?;
+        Some(self.decode(&id.to_be_bytes(), &v, true))
+    }
+
+    /// Decodes a value of `cases` stored under `key`, loading the full text
+    /// from `texts` when `with_text` and it is not inline.
+    pub fn decode(&self, key: &[u8], v: &[u8], with_text: bool) -> Case {
+        let mut case = Case::decode(v).unwrap();
+        let version = Case::version(v);
+        if with_text
+            && version >= 4
+            && let Some(text) = self.texts.get(key).unwrap()
+        {
+            let text = self.decompress(&text);
+            case.full_text = if version == 4 {
+                FullText::from_raw(std::str::from_utf8(&text).unwrap())
+            } else {
+                bincode::decode_from_slice(&text, standard()).unwrap().0
+            };
+        }
+        case
+    }
+
+    /// The plain-text beginning of the full text of case `id`.
+    pub fn preview(&self, id: u32) -> Option<String> {
+        if let Some(v) = self.previews.get(id.to_be_bytes()).unwrap() {
+            return Some(String::from_utf8(v.to_vec()).unwrap());
+        }
+        self.case(id).map(|case| preview_text(&case.full_text))
+    }
+
+    /// Adds writing `case` under `id` to `batch`, the full text split off.
+    pub fn insert(&self, batch: &mut Batch, id: u32, mut case: Case) {
+        let key = id.to_be_bytes();
+        let full_text = std::mem::take(&mut case.full_text);
+        batch.insert(&self.previews, key, preview_text(&full_text));
+        let text = bincode::encode_to_vec(&full_text, standard()).unwrap();
+        batch.insert(&self.texts, key, self.compress(text));
+        batch.insert(&self.cases, key, case.encode());
+    }
+
+    /// Adds removing case `id` to `batch`.
+    pub fn remove(&self, batch: &mut Batch, id: u32) {
+        let key = id.to_be_bytes();
+        batch.remove(&self.cases, key);
+        batch.remove(&self.previews, key);
+        batch.remove(&self.texts, key);
+        batch.remove(&self.to_index, key);
+    }
+
+    /// The new cases below `start` not indexed yet, stored after an index run
+    /// passed their ids.
+    pub fn unindexed_before(&self, start: u32) -> Vec<u32> {
+        self.to_index
+            .range(..start.to_be_bytes())
+            .map(|i| u32::from_be_bytes(i.unwrap().0[..].try_into().unwrap()))
+            .collect()
+    }
+
+    /// Adds taking the indexed `ids` off `to_index` to `batch`.
+    pub fn indexed(&self, batch: &mut Batch, ids: &[u32]) {
+        for id in ids {
+            batch.remove(&self.to_index, id.to_be_bytes());
+        }
+    }
+
+    /// Adds removing case `id` and recording it as withdrawn to `batch`.
+    pub fn withdraw(&self, batch: &mut Batch, id: u32) {
+        self.remove(batch, id);
+        batch.insert(
+            &self.withdrawn,
+            id.to_be_bytes(),
+            jiff::Timestamp::now().to_string(),
+        );
+    }
+
+    pub fn is_withdrawn(&self, id: u32) -> bool {
+        self.withdrawn.contains_key(id.to_be_bytes()).unwrap()
+    }
+
+    /// A value of `texts`, decompressed if needed.
+    pub fn decompress(&self, v: &[u8]) -> Vec<u8> {
+        if !v.starts_with(&ZSTD_MAGIC) {
+            return v.to_vec();
+        }
+        let id = zstd_safe::get_dict_id_from_frame(v).map_or(0, |id| id.get());
+        let dict = self
+            .decoders
+            .get(&id)
+            .unwrap_or_else(|| panic!("missing zstd dictionary {id}"));
+        let mut text = Vec::new();
+        Decoder::with_prepared_dictionary(v, dict)
+            .unwrap()
+            .read_to_end(&mut text)
+            .unwrap();
+        text
+    }
+
+    /// `text` as stored in `texts`: compressed with the active dictionary, or
+    /// as is without one.
+    pub fn compress(&self, text: Vec<u8>) -> Vec<u8> {
+        match &self.encoder {
+            Some((_, dict)) => Compressor::with_prepared_dictionary(dict)
+                .unwrap()
+                .compress(&text)
+                .unwrap(),
+            None => text,
+        }
+    }
+
+    pub fn has_dictionary(&self) -> bool {
+        self.encoder.is_some()
+    }
+
+    /// Whether a value of `texts` is compressed with the active dictionary.
+    pub fn is_compressed(&self, v: &[u8]) -> bool {
+        self.encoder.as_ref().is_some_and(|(id, _)| {
+            v.starts_with(&ZSTD_MAGIC)
+                && zstd_safe::get_dict_id_from_frame(v).map(|id| id.get()) == Some(*id)
+        })
+    }
+
+    /// Stores `dict` and makes it the dictionary of new texts, from the next
+    /// [`Store::open`] on. Returns its id.
+    pub fn add_dictionary(&self, dict: &[u8]) -> u32 {
+        let id = zstd_safe::get_dict_id_from_dict(dict)
+            .expect("not a zstd dictionary")
+            .get();
+        self.dicts.insert(id.to_be_bytes(), dict).unwrap();
+        self.dicts.insert(ACTIVE_DICT, id.to_be_bytes()).unwrap();
+        id
+    }
+}
+
+fn preview_text(full_text: &FullText) -> String {
+    full_text
+        .to_text()
+        .chars()
+        .take(PREVIEW_SOURCE_CHARS)
+        .collect()
+}
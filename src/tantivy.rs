@@ -1,22 +1,265 @@
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet},
+    ops::Bound,
+};
 
 use tantivy::{
-    IndexReader, ReloadPolicy,
+    DocId, IndexReader, Order, ReloadPolicy, Score, SegmentOrdinal, SegmentReader, TantivyDocument,
+    Term,
+    collector::{Collector, SegmentCollector},
+    columnar::Column,
     directory::MmapDirectory,
-    query::QueryParser,
+    query::{AllQuery, BooleanQuery, Occur, Query, QueryParser, RangeQuery, TermQuery},
     schema::{
-        Field, IndexRecordOption, NumericOptions, STORED, Schema, SchemaBuilder, TextFieldIndexing,
-        TextOptions,
+        FAST, Facet, FacetOptions, Field, INDEXED, IndexRecordOption, NumericOptions, STORED,
+        STRING, Schema, SchemaBuilder, TextFieldIndexing, TextOptions,
     },
-    tokenizer::{RemoveLongFilter, StopWordFilter, TextAnalyzer},
+    snippet::SnippetGenerator,
+    tokenizer::{RemoveLongFilter, StopWordFilter, TextAnalyzer, TokenStream},
 };
 
-use crate::CONFIG;
+use crate::{CONFIG, Case, court_level, date_number, normalize_case_no};
 
 pub struct Searcher {
     pub reader: IndexReader,
     pub query_parser: QueryParser,
     pub id: Field,
+    pub full_text: Field,
+}
+
+/// Length of the search result previews, in chars.
+pub const PREVIEW_CHARS: usize = 240;
+
+/// Most ids returned by an exact lookup.
+const LOOKUP_LIMIT: usize = 50;
+
+impl Searcher {
+    /// Snippet generator for `full_text` highlighting the terms of `query` from
+    /// every field, so it also works when the full text is not indexed.
+    pub fn snippet_generator(
+        &self,
+        searcher: &tantivy::Searcher,
+        query: &dyn Query,
+    ) -> tantivy::Result<SnippetGenerator> {
+        let mut terms = BTreeSet::new();
+        query.query_terms(&mut |term, _| {
+            terms.insert(term.clone());
+        });
+        let mut terms_text: BTreeMap<String, Score> = BTreeMap::new();
+        for term in terms {
+            let value = term.value();
+            let Some(text) = value.as_str() else {
+                continue;
+            };
+            let doc_freq = searcher.doc_freq(&term)?;
+            if doc_freq > 0 {
+                let score = 1.0 / (1.0 + doc_freq as Score);
+                let entry = terms_text.entry(text.to_string()).or_insert(score);
+                *entry = entry.max(score);
+            }
+        }
+        let tokenizer = searcher.index().tokenizer_for_field(self.full_text)?;
+        Ok(SnippetGenerator::new(
+            terms_text,
+            tokenizer,
+            self.full_text,
+            PREVIEW_CHARS,
+        ))
+    }
+
+    /// Matches documents whose `field` contains every token of `value`, or all
+    /// documents if `value` has no tokens besides stop words.
+    pub fn text_filter(&self, field: &str, value: &str) -> tantivy::Result<Box<dyn Query>> {
+        let searcher = self.reader.searcher();
+        let field = searcher.schema().get_field(field)?;
+        let mut tokenizer = searcher.index().tokenizer_for_field(field)?;
+        let mut stream = tokenizer.token_stream(value);
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        while let Some(token) = stream.next() {
+            let term = Term::from_field_text(field, &token.text);
+            clauses.push((
+                Occur::Must,
+                Box::new(TermQuery::new(term, IndexRecordOption::Basic)),
+            ));
+        }
+        if clauses.is_empty() {
+            return Ok(Box::new(AllQuery));
+        }
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+
+    /// Ids of the documents whose untokenized `field` equals `value`, newest
+    /// judgment first.
+    pub fn lookup(&self, field: &str, value: &str) -> tantivy::Result<Vec<u32>> {
+        let searcher = self.reader.searcher();
+        let field = searcher.schema().get_field(field)?;
+        let query = TermQuery::new(
+            Term::from_field_text(field, value),
+            IndexRecordOption::Basic,
+        );
+        let collector = SearchAfter {
+            limit: LOOKUP_LIMIT,
+            offset: 0,
+            after: None,
+            sort_by: Some(("judgment_ymd", Order::Desc)),
+        };
+        let hits = searcher.search(&query, &collector)?;
+        Ok(hits.into_iter().map(|(cursor, _)| cursor.id).collect())
+    }
+
+    /// Matches documents whose u64 `field` lies in `from..=to`.
+    pub fn range_filter(
+        &self,
+        field: &str,
+        from: Option<u64>,
+        to: Option<u64>,
+    ) -> tantivy::Result<Box<dyn Query>> {
+        let field = self.reader.searcher().schema().get_field(field)?;
+        let bound = |v: Option<u64>| match v {
+            Some(v) => Bound::Included(Term::from_field_u64(field, v)),
+            None => Bound::Unbounded,
+        };
+        Ok(Box::new(RangeQuery::new(bound(from), bound(to))))
+    }
+}
+
+/// Facet dimensions counted for each search, as `(path, label)`. Documents carry
+/// one `/path/value` facet per dimension in the `facet` field.
+pub const FACETS: [(&str, &str); 6] = [
+    ("region", "所属地区"),
+    ("court_level", "法院层级"),
+    ("court", "法院"),
+    ("case_type", "案件类型"),
+    ("procedure", "审理程序"),
+    ("year", "年份"),
+];
+
+/// Position of a hit in a result list: results are ordered by increasing
+/// `(rank, id)`, where the rank comes from the score or the sort field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cursor {
+    rank: u64,
+    pub id: u32,
+}
+
+impl Cursor {
+    /// Opaque string form, for links and the API.
+    pub fn encode(&self) -> String {
+        format!("{:016x}{:08x}", self.rank, self.id)
+    }
+
+    pub fn decode(s: &str) -> Option<Cursor> {
+        if s.len() != 24 || !s.is_ascii() {
+            return None;
+        }
+        Some(Cursor {
+            rank: u64::from_str_radix(&s[..16], 16).ok()?,
+            id: u32::from_str_radix(&s[16..], 16).ok()?,
+        })
+    }
+}
+
+/// Collects the top `limit` hits after skipping `offset` hits, or the hits
+/// following `after`. Sorted by score, or by a u64 fast field when `sort_by`
+/// is set, with ties broken by id and missing values last.
+///
+/// Hits are identified by the `id` fast field, so the cursor stays valid when
+/// segments are merged, and paging with `after` costs the same on every page.
+pub struct SearchAfter {
+    pub limit: usize,
+    pub offset: usize,
+    pub after: Option<Cursor>,
+    pub sort_by: Option<(&'static str, Order)>,
+}
+
+pub struct SearchAfterSegment {
+    size: usize,
+    after: Option<Cursor>,
+    ids: Column<u64>,
+    sort_by: Option<(Column<u64>, Order)>,
+    heap: BinaryHeap<(Cursor, u32)>,
+}
+
+impl Collector for SearchAfter {
+    /// Hits in order, with the BM25 score when sorted by relevance.
+    type Fruit = Vec<(Cursor, Score)>;
+    type Child = SearchAfterSegment;
+
+    fn for_segment(
+        &self,
+        _segment_local_id: SegmentOrdinal,
+        segment: &SegmentReader,
+    ) -> tantivy::Result<SearchAfterSegment> {
+        let fast_fields = segment.fast_fields();
+        let sort_by = match self.sort_by {
+            Some((field, order)) => Some((fast_fields.u64(field)?, order)),
+            None => None,
+        };
+        Ok(SearchAfterSegment {
+            size: self.limit + self.offset,
+            after: self.after,
+            ids: fast_fields.u64("id")?,
+            sort_by,
+            heap: BinaryHeap::new(),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        self.sort_by.is_none()
+    }
+
+    fn merge_fruits(
+        &self,
+        segment_fruits: Vec<Vec<(Cursor, Score)>>,
+    ) -> tantivy::Result<Self::Fruit> {
+        let mut hits: Vec<_> = segment_fruits.into_iter().flatten().collect();
+        hits.sort_by_key(|(cursor, _)| *cursor);
+        Ok(hits
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect())
+    }
+}
+
+impl SegmentCollector for SearchAfterSegment {
+    type Fruit = Vec<(Cursor, Score)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        let Some(id) = self.ids.first(doc) else {
+            return;
+        };
+        let rank = match &self.sort_by {
+            // Scores are non-negative, so their bits order like the floats.
+            None => u64::MAX - score.max(0.0).to_bits() as u64,
+            Some((column, order)) => match column.first(doc) {
+                None => u64::MAX,
+                Some(v) if order.is_asc() => v.min(u64::MAX - 1),
+                Some(v) => u64::MAX - 1 - v.min(u64::MAX - 1),
+            },
+        };
+        let cursor = Cursor {
+            rank,
+            id: id as u32,
+        };
+        if self.after.is_some_and(|after| cursor <= after) {
+            return;
+        }
+        if self.heap.len() < self.size {
+            self.heap.push((cursor, score.to_bits()));
+        } else if let Some(mut top) = self.heap.peek_mut()
+            && cursor < top.0
+        {
+            *top = (cursor, score.to_bits());
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.heap
+            .into_iter()
+            .map(|(cursor, score)| (cursor, Score::from_bits(score)))
+            .collect()
+    }
 }
 
 pub struct Tan;
@@ -28,23 +271,34 @@ impl Tan {
             .set_tokenizer("jieba")
             .set_index_option(IndexRecordOption::WithFreqsAndPositions);
         let num_options = NumericOptions::default().set_indexed();
+        let sort_options = NumericOptions::default().set_indexed().set_fast();
         let text_options_nostored = TextOptions::default().set_indexing_options(text_indexing);
-        schema_builder.add_u64_field("id", STORED);
+        // Indexed so that a single case can be deleted or replaced by id.
+        schema_builder.add_u64_field("id", STORED | FAST | INDEXED);
         schema_builder.add_text_field("case_id", text_options_nostored.clone());
         schema_builder.add_text_field("case_name", text_options_nostored.clone());
         schema_builder.add_text_field("court", text_options_nostored.clone());
+        schema_builder.add_text_field("region", text_options_nostored.clone());
         schema_builder.add_text_field("case_type", text_options_nostored.clone());
+        schema_builder.add_text_field("case_type_code", text_options_nostored.clone());
+        schema_builder.add_text_field("source", text_options_nostored.clone());
         schema_builder.add_text_field("procedure", text_options_nostored.clone());
         schema_builder.add_text_field("judgment_date", text_options_nostored.clone());
         schema_builder.add_u64_field("year", num_options.clone());
         schema_builder.add_u64_field("month", num_options.clone());
         schema_builder.add_u64_field("day", num_options);
         schema_builder.add_text_field("public_date", text_options_nostored.clone());
+        schema_builder.add_u64_field("judgment_ymd", sort_options.clone());
+        schema_builder.add_u64_field("public_ymd", sort_options);
         schema_builder.add_text_field("parties", text_options_nostored.clone());
         schema_builder.add_text_field("cause", text_options_nostored.clone());
 
         schema_builder.add_text_field("legal_basis", text_options_nostored.clone());
         schema_builder.add_text_field("full_text", text_options_nostored);
+        schema_builder.add_facet_field("facet", FacetOptions::default());
+        // Untokenized keys for exact lookup, `case_id_raw` is normalized.
+        schema_builder.add_text_field("case_id_raw", STRING);
+        schema_builder.add_text_field("doc_id", STRING);
         schema_builder.build()
     }
 
@@ -80,7 +334,10 @@ impl Tan {
         let case_id = schema.get_field("case_id")?;
         let case_name = schema.get_field("case_name")?;
         let court = schema.get_field("court")?;
+        let region = schema.get_field("region")?;
         let case_type = schema.get_field("case_type")?;
+        let case_type_code = schema.get_field("case_type_code")?;
+        let source = schema.get_field("source")?;
         let cause = schema.get_field("cause")?;
         let legal_basis = schema.get_field("legal_basis")?;
         let parties = schema.get_field("parties")?;
@@ -97,7 +354,10 @@ impl Tan {
             case_id,
             case_name,
             court,
+            region,
             case_type,
+            case_type_code,
+            source,
             cause,
             legal_basis,
             parties,
@@ -128,6 +388,299 @@ impl Tan {
             reader,
             query_parser,
             id,
+            full_text,
         })
     }
 }
+
+/// The indexed fields of a case, to build its document.
+pub struct CaseFields {
+    pub id: Field,
+    case_id: Field,
+    case_name: Field,
+    court: Field,
+    region: Field,
+    case_type: Field,
+    case_type_code: Field,
+    source: Field,
+    procedure: Field,
+    year: Field,
+    month: Field,
+    day: Field,
+    judgment_date: Field,
+    public_date: Field,
+    judgment_ymd: Field,
+    public_ymd: Field,
+    parties: Field,
+    cause: Field,
+    legal_basis: Field,
+    full_text: Field,
+    facet: Field,
+    case_id_raw: Field,
+    doc_id: Field,
+}
+
+impl CaseFields {
+    pub fn new(schema: &Schema) -> CaseFields {
+        let field = |name| schema.get_field(name).unwrap();
+        CaseFields {
+            id: field("id"),
+            case_id: field("case_id"),
+            case_name: field("case_name"),
+            court: field("court"),
+            region: field("region"),
+            case_type: field("case_type"),
+            case_type_code: field("case_type_code"),
+            source: field("source"),
+            procedure: field("procedure"),
+            year: field("year"),
+            month: field("month"),
+            day: field("day"),
+            judgment_date: field("judgment_date"),
+            public_date: field("public_date"),
+            judgment_ymd: field("judgment_ymd"),
+            public_ymd: field("public_ymd"),
+            parties: field("parties"),
+            cause: field("cause"),
+            legal_basis: field("legal_basis"),
+            full_text: field("full_text"),
+            facet: field("facet"),
+            case_id_raw: field("case_id_raw"),
+            doc_id: field("doc_id"),
+        }
+    }
+
+    /// Term matching the document of case `id`.
+    pub fn id_term(&self, id: u32) -> Term {
+        Term::from_field_u64(self.id, id as u64)
+    }
+
+    /// The document of case `id`. The full text is indexed only with
+    /// `index_with_full_text`.
+    pub fn document(&self, id: u32, case: &Case) -> TantivyDocument {
+        let mut doc = TantivyDocument::default();
+        doc.add_u64(self.id, id as u64);
+        if !case.case_id.is_empty() {
+            doc.add_text(self.case_id, &case.case_id);
+            doc.add_text(self.case_id_raw, normalize_case_no(&case.case_id));
+        }
+        if !case.doc_id.is_empty() {
+            doc.add_text(self.doc_id, &case.doc_id);
+        }
+        if !case.case_name.is_empty() {
+            doc.add_text(self.case_name, &case.case_name);
+        }
+        if !case.court.is_empty() {
+            doc.add_text(self.court, &case.court);
+            doc.add_facet(self.facet, Facet::from_path(["court", &case.court]));
+            doc.add_facet(
+                self.facet,
+                Facet::from_path(["court_level", court_level(&case.court)]),
+            );
+        }
+        if !case.region.is_empty() {
+            doc.add_text(self.region, &case.region);
+            doc.add_facet(self.facet, Facet::from_path(["region", &case.region]));
+        }
+        if !case.case_type.is_empty() {
+            doc.add_text(self.case_type, &case.case_type);
+            doc.add_facet(self.facet, Facet::from_path(["case_type", &case.case_type]));
+        }
+        if !case.case_type_code.is_empty() {
+            doc.add_text(self.case_type_code, &case.case_type_code);
+        }
+        if !case.source.is_empty() {
+            doc.add_text(self.source, &case.source);
+        }
+        if !case.procedure.is_empty() {
+            doc.add_text(self.procedure, &case.procedure);
+            doc.add_facet(self.facet, Facet::from_path(["procedure", &case.procedure]));
+        }
+        if let Some(date) = case.judgment_date {
+            doc.add_text(self.judgment_date, date.to_string());
+            doc.add_u64(self.judgment_ymd, date_number(date));
+            doc.add_u64(self.year, date.year() as u64);
+            doc.add_u64(self.month, date.month() as u64);
+            doc.add_u64(self.day, date.day() as u64);
+            doc.add_facet(
+                self.facet,
+                Facet::from_path(["year", &date.year().to_string()]),
+            );
+        }
+        if let Some(date) = case.public_date {
+            doc.add_text(self.public_date, date.to_string());
+            doc.add_u64(self.public_ymd, date_number(date));
+        }
+        for party in &case.parties {
+            doc.add_text(self.parties, party);
+        }
+        if !case.cause.is_empty() {
+            doc.add_text(self.cause, &case.cause);
+        }
+        for basis in &case.legal_basis {
+            doc.add_text(self.legal_basis, basis);
+        }
+        if CONFIG.index_with_full_text && !case.full_text.is_empty() {
+            doc.add_text(self.full_text, case.full_text.to_text());
+        }
+        doc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use tantivy::{Index, IndexWriter, doc, schema::TEXT};
+
+    use super::*;
+
+    /// Day of case `id`, none for every fifth case and shared by others.
+    fn day(id: u64) -> Option<u64> {
+        (!id.is_multiple_of(5)).then_some(20200100 + id % 7)
+    }
+
+    /// Cases 0 to 39 over several segments, their text repeating a term up to
+    /// four times so scores tie too.
+    fn index() -> Index {
+        let mut schema = SchemaBuilder::default();
+        let id = schema.add_u64_field("id", FAST | INDEXED);
+        let ymd = schema.add_u64_field("judgment_ymd", FAST);
+        let text = schema.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema.build());
+        let mut writer: IndexWriter = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        for i in 0..40u64 {
+            let body = vec!["判决"; i as usize % 4 + 1].join(" ");
+            let mut doc = doc!(id => i, text => body);
+            if let Some(day) = day(i) {
+                doc.add_u64(ymd, day);
+            }
+            writer.add_document(doc).unwrap();
+            if i % 15 == 14 {
+                writer.commit().unwrap();
+            }
+        }
+        writer.commit().unwrap();
+        index
+    }
+
+    fn search(
+        index: &Index,
+        query: &dyn Query,
+        sort_by: Option<(&'static str, Order)>,
+        limit: usize,
+        after: Option<Cursor>,
+    ) -> Vec<(Cursor, Score)> {
+        let collector = SearchAfter {
+            limit,
+            offset: 0,
+            after,
+            sort_by,
+        };
+        let searcher = index.reader().unwrap().searcher();
+        searcher.search(query, &collector).unwrap()
+    }
+
+    fn ids(hits: &[(Cursor, Score)]) -> Vec<u32> {
+        hits.iter().map(|(cursor, _)| cursor.id).collect()
+    }
+
+    #[test]
+    fn cursor_round_trip() {
+        for cursor in [
+            Cursor { rank: 0, id: 0 },
+            Cursor {
+                rank: 20210315,
+                id: 123456,
+            },
+            Cursor {
+                rank: u64::MAX,
+                id: u32::MAX,
+            },
+        ] {
+            assert_eq!(Cursor::decode(&cursor.encode()), Some(cursor));
+        }
+        assert_eq!(Cursor::decode(""), None);
+        assert_eq!(Cursor::decode("00000000000000000000000"), None);
+        assert_eq!(Cursor::decode("0000000000000000000000zz"), None);
+        assert_eq!(Cursor::decode("00000000000000000000000é"), None);
+    }
+
+    #[test]
+    fn rank_by_score() {
+        let index = index();
+        let field = index.schema().get_field("text").unwrap();
+        let query = TermQuery::new(
+            Term::from_field_text(field, "判决"),
+            IndexRecordOption::WithFreqs,
+        );
+        let hits = search(&index, &query, None, 100, None);
+        assert_eq!(hits.len(), 40);
+        for pair in hits.windows(2) {
+            let ((a, a_score), (b, b_score)) = (pair[0], pair[1]);
+            assert!(a_score > b_score || a_score == b_score && a.id < b.id);
+        }
+        // Four occurrences score highest.
+        assert_eq!(ids(&hits[..3]), [3, 7, 11]);
+    }
+
+    #[test]
+    fn rank_by_date() {
+        let index = index();
+        let mut expected: Vec<u64> = (0..40).collect();
+
+        expected.sort_by_key(|&id| (day(id).is_none(), day(id), id));
+        let hits = search(
+            &index,
+            &AllQuery,
+            Some(("judgment_ymd", Order::Asc)),
+            100,
+            None,
+        );
+        assert_eq!(
+            ids(&hits),
+            expected.iter().map(|&id| id as u32).collect::<Vec<_>>()
+        );
+
+        expected.sort_by_key(|&id| (day(id).is_none(), Reverse(day(id)), id));
+        let hits = search(
+            &index,
+            &AllQuery,
+            Some(("judgment_ymd", Order::Desc)),
+            100,
+            None,
+        );
+        assert_eq!(
+            ids(&hits),
+            expected.iter().map(|&id| id as u32).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn pages_after_cursor() {
+        let index = index();
+        let field = index.schema().get_field("text").unwrap();
+        let query = TermQuery::new(
+            Term::from_field_text(field, "判决"),
+            IndexRecordOption::WithFreqs,
+        );
+        for sort_by in [
+            None,
+            Some(("judgment_ymd", Order::Asc)),
+            Some(("judgment_ymd", Order::Desc)),
+        ] {
+            let all = search(&index, &query, sort_by, 100, None);
+            let mut paged = Vec::new();
+            let mut after = None;
+            loop {
+                let page = search(&index, &query, sort_by, 7, after);
+                let Some(&(last, _)) = page.last() else {
+                    break;
+                };
+                paged.extend(page);
+                after = Some(last);
+            }
+            assert_eq!(ids(&paged), ids(&all));
+        }
+    }
+}
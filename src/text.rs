@@ -0,0 +1,285 @@
+//! The full text of a judgment as typed paragraphs, rendered to HTML for the
+//! case page and to plain text for indexing and previews.
+//!
+//! Sources give either whitespace separated plain text, one paragraph per
+//! token, or the HTML of wenshu and of the exports, which is parsed once when
+//! the case is converted.
+
+use bincode::{Decode, Encode};
+use scraper::{ElementRef, Html};
+use serde::{Deserialize, Serialize};
+
+use crate::{escape_html, parse_case_no};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Court, document type and case number above the body.
+    Header,
+    Body,
+    /// Judges, date and clerk below the body.
+    Signature,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub struct Paragraph {
+    pub kind: Kind,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Encode, Decode, Serialize, Deserialize)]
+pub struct FullText(pub Vec<Paragraph>);
+
+/// Lines at the top searched for the case number ending the header.
+const HEADER_LINES: usize = 5;
+
+/// Lines starting the signature block, which ends after the clerk lines.
+const SIGNATURE_START: [&str; 3] = ["审判员", "审判长", "执行长"];
+const CLERK: &str = "书记员";
+
+/// Elements whose content starts a new line.
+const BLOCK_TAGS: [&str; 14] = [
+    "div", "p", "br", "table", "tr", "td", "th", "li", "h1", "h2", "h3", "h4", "h5", "h6",
+];
+
+impl FullText {
+    /// Reads a source full text, HTML if it starts with a tag.
+    pub fn from_raw(raw: &str) -> FullText {
+        if raw.trim_start().starts_with('<') {
+            FullText::from_html(raw)
+        } else {
+            FullText::from_plain(raw)
+        }
+    }
+
+    /// Whitespace separated paragraphs, as in the torrent CSVs.
+    pub fn from_plain(text: &str) -> FullText {
+        classify(text.split_whitespace().map(|line| (None, line.to_owned())))
+    }
+
+    /// The text lines of `html`. Whatever precedes the `c_header` block is
+    /// dropped, and the `c_header` and `c_right` classes mark the header and
+    /// the signature.
+    pub fn from_html(html: &str) -> FullText {
+        let fragment = Html::parse_fragment(html);
+        let mut lines = Lines::default();
+        lines.collect(fragment.root_element(), None);
+        lines.flush(None);
+        classify(lines.lines)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Headers in `c_header` blocks, the signature in `c_right` blocks and
+    /// the body in `<p>`, the classes the case page styles.
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        for paragraph in &self.0 {
+            let (open, close) = match paragraph.kind {
+                Kind::Header => ("<div class=\"c_header\">", "</div>"),
+                Kind::Body => ("<p>", "</p>"),
+                Kind::Signature => ("<div class=\"c_right\">", "</div>"),
+            };
+            html.push_str(open);
+            escape_html(&paragraph.text, &mut html);
+            html.push_str(close);
+        }
+        html
+    }
+
+    /// The paragraphs, one per line.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for paragraph in &self.0 {
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(&paragraph.text);
+        }
+        text
+    }
+}
+
+/// Text lines of an HTML document, with the kind its classes suggest.
+#[derive(Default)]
+struct Lines {
+    lines: Vec<(Option<Kind>, String)>,
+    line: String,
+    seen_header: bool,
+}
+
+impl Lines {
+    fn collect(&mut self, element: ElementRef, hint: Option<Kind>) {
+        for child in element.children() {
+            if let Some(text) = child.value().as_text() {
+                self.line.push_str(text);
+            } else if let Some(child) = ElementRef::wrap(child) {
+                let name = child.value().name();
+                if matches!(name, "script" | "style" | "template") {
+                    continue;
+                }
+                let mut child_hint = hint;
+                for class in child.value().classes() {
+                    match class {
+                        "c_header" => {
+                            if !self.seen_header {
+                                self.seen_header = true;
+                                self.lines.clear();
+                                self.line.clear();
+                            }
+                            child_hint = Some(Kind::Header);
+                        }
+                        "c_right" => child_hint = Some(Kind::Signature),
+                        _ => {}
+                    }
+                }
+                let block = BLOCK_TAGS.contains(&name);
+                if block {
+                    self.flush(hint);
+                }
+                self.collect(child, child_hint);
+                if block {
+                    self.flush(child_hint);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self, hint: Option<Kind>) {
+        let line = self.line.trim();
+        if !line.is_empty() {
+            self.lines.push((hint, line.to_owned()));
+        }
+        self.line.clear();
+    }
+}
+
+/// Types the lines: the top lines up to the case number are the header, the
+/// lines from the judges to the clerk are the signature, unless the source
+/// marked them already.
+fn classify(lines: impl IntoIterator<Item = (Option<Kind>, String)>) -> FullText {
+    let lines: Vec<_> = lines.into_iter().collect();
+    let header_end = lines
+        .iter()
+        .take(HEADER_LINES)
+        .position(|(_, line)| parse_case_no(line).is_some());
+
+    let mut started = false;
+    let mut in_clerk_block = false;
+    let mut paragraphs = Vec::with_capacity(lines.len());
+    for (i, (hint, text)) in lines.into_iter().enumerate() {
+        let compact: String = text
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '　')
+            .collect();
+        let is_start = SIGNATURE_START.iter().any(|k| compact.starts_with(k));
+        let is_clerk = compact.starts_with(CLERK);
+        if !started && is_start {
+            started = true;
+        }
+        if started && is_clerk {
+            in_clerk_block = true;
+        }
+        let signature = started && (!in_clerk_block || is_clerk);
+        if in_clerk_block && !is_clerk {
+            started = false;
+            in_clerk_block = false;
+        }
+
+        let kind = match hint {
+            Some(kind) => kind,
+            None if header_end.is_some_and(|end| i <= end) => Kind::Header,
+            None if signature => Kind::Signature,
+            None => Kind::Body,
+        };
+        paragraphs.push(Paragraph { kind, text });
+    }
+    FullText(paragraphs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(text: &FullText) -> Vec<(Kind, &str)> {
+        text.0.iter().map(|p| (p.kind, p.text.as_str())).collect()
+    }
+
+    #[test]
+    fn classifies_plain_text() {
+        let text = FullText::from_plain(
+            "北京市第一中级人民法院 民事判决书 （2021）京01民终1234号 上诉人张三。 本院认为，上诉理由不能成立。 \
+             审判长张三 审判员李四 二〇二一年三月十五日 书记员王五 附：本案适用的法律",
+        );
+        assert_eq!(
+            kinds(&text),
+            [
+                (Kind::Header, "北京市第一中级人民法院"),
+                (Kind::Header, "民事判决书"),
+                (Kind::Header, "（2021）京01民终1234号"),
+                (Kind::Body, "上诉人张三。"),
+                (Kind::Body, "本院认为，上诉理由不能成立。"),
+                (Kind::Signature, "审判长张三"),
+                (Kind::Signature, "审判员李四"),
+                (Kind::Signature, "二〇二一年三月十五日"),
+                (Kind::Signature, "书记员王五"),
+                (Kind::Body, "附：本案适用的法律"),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_header_without_a_case_number_near_the_top() {
+        let text = FullText::from_plain("一 二 三 四 五 （2021）京01民终1234号");
+        assert!(text.0.iter().all(|p| p.kind == Kind::Body));
+    }
+
+    #[test]
+    fn classifies_html_with_hints() {
+        let text = FullText::from_raw(
+            r#"<div>导航</div><div class="c_header">北京市第一中级人民法院</div>
+            <div class="c_header">民事判决书</div><div>（2021）京01民终1234号</div>
+            <p>本院认为，a &lt; b。</p><div class="c_right">审判长　张三</div>
+            <div>书记员　王五</div><div><span>附</span>：法律条文</div>"#,
+        );
+        assert_eq!(
+            kinds(&text),
+            [
+                (Kind::Header, "北京市第一中级人民法院"),
+                (Kind::Header, "民事判决书"),
+                (Kind::Header, "（2021）京01民终1234号"),
+                (Kind::Body, "本院认为，a < b。"),
+                (Kind::Signature, "审判长　张三"),
+                (Kind::Signature, "书记员　王五"),
+                (Kind::Body, "附：法律条文"),
+            ]
+        );
+    }
+
+    #[test]
+    fn renders_html_and_text() {
+        let text = FullText(vec![
+            Paragraph {
+                kind: Kind::Header,
+                text: "民事判决书".to_owned(),
+            },
+            Paragraph {
+                kind: Kind::Body,
+                text: "a < b & c".to_owned(),
+            },
+            Paragraph {
+                kind: Kind::Signature,
+                text: "书记员王五".to_owned(),
+            },
+        ]);
+        assert_eq!(
+            text.to_html(),
+            "<div class=\"c_header\">民事判决书</div><p>a &lt; b &amp; c</p>\
+             <div class=\"c_right\">书记员王五</div>"
+        );
+        assert_eq!(text.to_text(), "民事判决书\na < b & c\n书记员王五");
+        assert_eq!(FullText::from_raw("").to_html(), "");
+    }
+}